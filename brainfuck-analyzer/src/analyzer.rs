@@ -106,6 +106,10 @@ impl<'a> CharsWithPosition<'a> {
         }
         c_option
     }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.clone().next()
+    }
 }
 
 impl TokenGroup {
@@ -150,6 +154,20 @@ fn _flat_parse(result: &mut Vec<Token>, tokens: &Vec<Token>) {
 }
 
 pub fn parse(str: &str) -> Result<ParseResult> {
+    let (parse_result, mut errors) = parse_with_recovery(str);
+    if errors.is_empty() {
+        Ok(parse_result)
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+/// Parse the whole input without stopping at the first syntax error.
+///
+/// Every unmatched `[`/`]`, unterminated paragraph comment and invalid token is reported,
+/// ordered by position. The returned token group is a best-effort tree: unclosed `[` are
+/// closed at the end of input, while stray `]` and invalid tokens are dropped.
+pub fn parse_with_recovery(str: &str) -> (ParseResult, Vec<ParseError>) {
     let chars = str.chars();
     let mut chars_with_position = CharsWithPosition {
         last_position: None,
@@ -161,7 +179,17 @@ pub fn parse(str: &str) -> Result<ParseResult> {
         state: ParseState::BrainFuck,
     };
 
-    _parse(&mut chars_with_position, true)
+    let mut errors = Vec::new();
+    let token_group = _parse(&mut chars_with_position, None, &mut errors);
+    errors.sort_by_key(|e| e.range.start);
+
+    (
+        ParseResult {
+            position: chars_with_position.position,
+            parse_token_group: token_group,
+        },
+        errors,
+    )
 }
 
 pub fn token_to_char(token: &Token) -> char {
@@ -178,18 +206,43 @@ pub fn token_to_char(token: &Token) -> char {
     }
 }
 
-fn _parse(chars: &mut CharsWithPosition, is_top: bool) -> Result<ParseResult> {
+// `loop_start` is the range of the `[` opening this group, or None for the top level.
+fn _parse(
+    chars: &mut CharsWithPosition,
+    loop_start: Option<Range>,
+    errors: &mut Vec<ParseError>,
+) -> TokenGroup {
     let mut v = Vec::new();
-    let mut stopped = false;
+    let mut closed = false;
     while let Some(c) = chars.next() {
         match &mut chars.state {
             ParseState::BrainFuck => {
                 let start = chars.last_position.unwrap_or_default();
                 let res = match c {
-                    '[' => TokenType::SubGroup(Box::new(_parse(chars, false)?.parse_token_group)),
+                    '[' => {
+                        let sub_group = _parse(
+                            chars,
+                            Some(Range {
+                                start,
+                                end: chars.position,
+                            }),
+                            errors,
+                        );
+                        TokenType::SubGroup(Box::new(sub_group))
+                    }
                     ']' => {
-                        stopped = true;
-                        break;
+                        if loop_start.is_some() {
+                            closed = true;
+                            break;
+                        }
+                        errors.push(ParseError {
+                            range: Range {
+                                start,
+                                end: chars.position,
+                            },
+                            error_message: "More ] found".to_string(),
+                        });
+                        continue;
                     }
                     '>' => TokenType::PointerIncrement,
                     '<' => TokenType::PointerDecrement,
@@ -198,33 +251,37 @@ fn _parse(chars: &mut CharsWithPosition, is_top: bool) -> Result<ParseResult> {
                     '.' => TokenType::Output,
                     ',' => TokenType::Input,
                     ' ' | '\n' | '\t' | '\r' => continue,
-                    '/' => match chars.next() {
+                    '/' => match chars.peek() {
                         Some('/') => {
+                            chars.next();
                             chars.state = ParseState::LineComment((start, "//".to_string()));
                             continue;
                         }
                         Some('*') => {
+                            chars.next();
                             chars.state = ParseState::ParagraphComment((start, "/*".to_string()));
                             continue;
                         }
                         _ => {
-                            return Err(ParseError {
+                            errors.push(ParseError {
                                 range: Range {
-                                    start: chars.last_position.unwrap_or_default(),
-                                    end: chars.last_position.unwrap_or_default(),
+                                    start,
+                                    end: chars.position,
                                 },
                                 error_message: "Invalid token".to_string(),
-                            })
+                            });
+                            continue;
                         }
                     },
                     _ => {
-                        return Err(ParseError {
+                        errors.push(ParseError {
                             range: Range {
-                                start: chars.last_position.unwrap_or_default(),
-                                end: chars.last_position.unwrap_or_default(),
+                                start,
+                                end: chars.position,
                             },
                             error_message: "Invalid token".to_string(),
-                        })
+                        });
+                        continue;
                     }
                 };
                 let range = Range {
@@ -271,46 +328,45 @@ fn _parse(chars: &mut CharsWithPosition, is_top: bool) -> Result<ParseResult> {
         }
     }
 
-    if let ParseState::LineComment((start_position, org_str)) = &chars.state {
-        v.push(Token {
-            range: Range {
-                start: *start_position,
+    // Only the innermost group reaches here with a pending comment, flush it and let the
+    // outer groups see plain end of input.
+    match std::mem::replace(&mut chars.state, ParseState::BrainFuck) {
+        ParseState::LineComment((start_position, org_str)) => {
+            v.push(Token {
+                range: Range {
+                    start: start_position,
+                    end: chars.position,
+                },
+                token_type: TokenType::Comment(org_str),
+            });
+        }
+        ParseState::ParagraphComment((start_position, org_str)) => {
+            let range = Range {
+                start: start_position,
                 end: chars.position,
-            },
-            token_type: TokenType::Comment(org_str.clone()),
-        });
+            };
+            errors.push(ParseError {
+                range,
+                error_message: "Paragraph comment missing end flag.".to_string(),
+            });
+            v.push(Token {
+                range,
+                token_type: TokenType::Comment(org_str),
+            });
+        }
+        ParseState::BrainFuck => (),
     }
 
-    if let ParseState::ParagraphComment(_) = &chars.state {
-        Err(ParseError {
-            range: Range {
-                start: chars.last_position.unwrap_or_default(),
-                end: chars.last_position.unwrap_or_default(),
-            },
-            error_message: "Paragraph comment missing end flag.".to_string(),
-        })
-    } else if is_top && chars.next().is_some() {
-        Err(ParseError {
-            range: Range {
-                start: chars.last_position.unwrap_or_default(),
-                end: chars.last_position.unwrap_or_default(),
-            },
-            error_message: "More ] found".to_string(),
-        })
-    } else if (!is_top && !stopped) || (is_top && stopped) {
-        Err(ParseError {
-            range: Range {
-                start: chars.last_position.unwrap_or_default(),
-                end: chars.last_position.unwrap_or_default(),
-            },
-            error_message: "More [ found".to_string(),
-        })
-    } else {
-        Ok(ParseResult {
-            position: chars.position,
-            parse_token_group: TokenGroup { token_group: v },
-        })
+    if let Some(loop_start) = loop_start {
+        if !closed {
+            errors.push(ParseError {
+                range: loop_start,
+                error_message: "More [ found".to_string(),
+            });
+        }
     }
+
+    TokenGroup { token_group: v }
 }
 
 #[test]
@@ -383,3 +439,76 @@ fn parse_more_stop_should_error() {
 fn parse_success() {
     assert_eq!(true, parse("[\r\n    >\r\n    >\r\n    ,\r\n][]").is_ok());
 }
+
+#[test]
+fn parse_with_recovery_should_report_all_errors() {
+    let (actual, errors) = parse_with_recovery("]+[>a/+\n[-");
+
+    let messages: Vec<&str> = errors.iter().map(|e| e.error_message.as_str()).collect();
+    assert_eq!(
+        vec![
+            "More ] found",
+            "More [ found",
+            "Invalid token",
+            "Invalid token",
+            "More [ found"
+        ],
+        messages
+    );
+    assert_eq!(
+        Range::new(Position::new(0, 0), Position::new(0, 1)),
+        errors[0].range
+    );
+    assert_eq!(
+        Range::new(Position::new(0, 2), Position::new(0, 3)),
+        errors[1].range
+    );
+    assert_eq!(
+        Range::new(Position::new(1, 0), Position::new(1, 1)),
+        errors[4].range
+    );
+
+    // best-effort tree: "+" followed by an unclosed group holding ">", "+" and another unclosed group
+    assert_eq!(2, actual.parse_token_group.tokens().len());
+    match &actual.parse_token_group.tokens()[1].token_type {
+        TokenType::SubGroup(tg) => {
+            assert_eq!(3, tg.tokens().len());
+            assert!(
+                matches!(&tg.tokens()[2].token_type, TokenType::SubGroup(x) if x.tokens().len() == 1)
+            );
+        }
+        _ => panic!("expected a sub group"),
+    }
+}
+
+#[test]
+fn parse_with_recovery_should_keep_unterminated_comment() {
+    let (actual, errors) = parse_with_recovery("[>/*todo");
+
+    assert_eq!(2, errors.len());
+    assert_eq!("More [ found", errors[0].error_message);
+    assert_eq!(
+        "Paragraph comment missing end flag.",
+        errors[1].error_message
+    );
+    match &actual.parse_token_group.tokens()[0].token_type {
+        TokenType::SubGroup(tg) => {
+            assert_eq!(
+                TokenType::Comment("/*todo".to_string()),
+                tg.tokens()[1].token_type
+            )
+        }
+        _ => panic!("expected a sub group"),
+    }
+}
+
+#[test]
+fn parse_with_recovery_without_error() {
+    let (actual, errors) = parse_with_recovery(">[[]<]//end");
+
+    assert!(errors.is_empty());
+    assert_eq!(
+        parse(">[[]<]//end").unwrap().parse_token_group,
+        actual.parse_token_group
+    );
+}
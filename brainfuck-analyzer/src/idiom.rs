@@ -0,0 +1,175 @@
+use crate::{Instruction, Op, Range};
+
+/// Rewrite common loop idioms in lowered instructions into dedicated operations.
+///
+/// A loop whose body only adds and moves, returns to its starting cell and decrements it by
+/// exactly one per iteration becomes a list of `MulAdd` followed by `SetZero`. Single step
+/// pointer loops become `ScanRight`/`ScanLeft`. Every generated instruction keeps the source
+/// range of the loop it replaces.
+pub fn recognize_idioms(instructions: &[Instruction]) -> Vec<Instruction> {
+    recognize_idioms_with(instructions, true)
+}
+
+/// `recognize_idioms` for instructions lowered with `lower_with`. When cells do not wrap, a loop is
+/// only rewritten when no cell is both increased and decreased in its body and the starting cell
+/// is decremented by a single `-`: any other mix may fail halfway through an iteration.
+pub fn recognize_idioms_with(instructions: &[Instruction], cells_wrap: bool) -> Vec<Instruction> {
+    let mut result = Vec::new();
+    for instruction in instructions {
+        match &instruction.op {
+            Op::Loop(body) => {
+                let body = recognize_idioms_with(body, cells_wrap);
+                match rewrite_loop(&body, &instruction.ranges, cells_wrap) {
+                    Some(mut rewritten) => result.append(&mut rewritten),
+                    None => result.push(Instruction {
+                        op: Op::Loop(body),
+                        ranges: instruction.ranges.clone(),
+                    }),
+                }
+            }
+            _ => result.push(instruction.clone()),
+        }
+    }
+    result
+}
+
+fn rewrite_loop(
+    body: &[Instruction],
+    ranges: &[Range],
+    cells_wrap: bool,
+) -> Option<Vec<Instruction>> {
+    let instruction = |op| Instruction {
+        op,
+        ranges: ranges.to_vec(),
+    };
+
+    match body {
+        [Instruction {
+            op: Op::Move(1), ..
+        }] => return Some(vec![instruction(Op::ScanRight)]),
+        [Instruction {
+            op: Op::Move(-1), ..
+        }] => return Some(vec![instruction(Op::ScanLeft)]),
+        _ => (),
+    }
+
+    // (offset, accumulated add) in order of first appearance
+    let mut deltas: Vec<(isize, i32)> = Vec::new();
+    let mut offset = 0;
+    for i in body {
+        match i.op {
+            Op::Add(n) => match deltas.iter_mut().find(|(o, _)| *o == offset) {
+                // a second run at the starting cell, or one turning around, may fail on its own
+                Some(_) if !cells_wrap && offset == 0 => return None,
+                Some((_, delta)) if !cells_wrap && delta.signum() != n.signum() => return None,
+                Some((_, delta)) => *delta = delta.wrapping_add(n),
+                None => deltas.push((offset, n)),
+            },
+            Op::Move(n) => offset += n,
+            _ => return None,
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+
+    let origin_delta = deltas
+        .iter()
+        .find(|(o, _)| *o == 0)
+        .map(|(_, delta)| *delta)
+        .unwrap_or(0);
+    let others: Vec<&(isize, i32)> = deltas.iter().filter(|(o, d)| *o != 0 && *d != 0).collect();
+
+    match origin_delta {
+        -1 => {
+            let mut result: Vec<Instruction> = others
+                .into_iter()
+                .map(|(o, d)| instruction(Op::MulAdd(*o, *d)))
+                .collect();
+            result.push(instruction(Op::SetZero));
+            Some(result)
+        }
+        // `[+]` only reaches zero by wrapping around, which may be an overflow error, keep it a loop
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+fn optimized_ops(input: &str) -> Vec<Op> {
+    let parse_result = crate::parse(input).unwrap();
+    recognize_idioms(&crate::lower(&parse_result.parse_token_group))
+        .into_iter()
+        .map(|i| i.op)
+        .collect()
+}
+
+#[test]
+fn recognize_set_zero() {
+    assert_eq!(vec![Op::Add(3), Op::SetZero], optimized_ops("+++[-]"));
+    assert!(matches!(optimized_ops("[+]")[..], [Op::Loop(_)]));
+}
+
+#[test]
+fn recognize_mul_add() {
+    assert_eq!(vec![Op::MulAdd(1, 1), Op::SetZero], optimized_ops("[->+<]"));
+    assert_eq!(
+        vec![Op::MulAdd(2, 3), Op::MulAdd(-1, -1), Op::SetZero],
+        optimized_ops("[>>+++<<<->+--]")
+    );
+}
+
+#[test]
+fn recognize_scan() {
+    assert_eq!(
+        vec![Op::ScanRight, Op::Move(-1), Op::ScanLeft],
+        optimized_ops("[>]<[<]")
+    );
+}
+
+#[test]
+fn keep_loop_not_matching_idiom() {
+    // unbalanced, contains output, and decrements by two
+    for input in ["[->+]", "[->.<]", "[-->+<]"] {
+        assert!(matches!(optimized_ops(input)[..], [Op::Loop(_)]));
+    }
+
+    // inner loop is rewritten even when the outer one is kept
+    match &optimized_ops("[>[-]<-.]")[..] {
+        [Op::Loop(body)] => assert_eq!(Op::SetZero, body[1].op),
+        _ => panic!("expected a loop"),
+    }
+}
+
+#[test]
+fn keep_loop_failing_without_wrapping() {
+    let rewrite = |input| {
+        let parse_result = crate::parse(input).unwrap();
+        let lowered = crate::lower_with(&parse_result.parse_token_group, false);
+        recognize_idioms_with(&lowered, false)
+            .into_iter()
+            .map(|i| i.op)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        vec![Op::MulAdd(1, 3), Op::MulAdd(2, -2), Op::SetZero],
+        rewrite("[>++>-<<->+>-<<]")
+    );
+    for input in ["[-->+<+]", "[->+<-+]", "[->+<+-]", "[>+<->-<]"] {
+        assert!(matches!(rewrite(input)[..], [Op::Loop(_)]), "on {}", input);
+    }
+}
+
+#[test]
+fn keep_loop_range() {
+    let parse_result = crate::parse("+\n[->++<]").unwrap();
+    let actual = recognize_idioms(&crate::lower(&parse_result.parse_token_group));
+
+    assert_eq!(3, actual.len());
+    for i in &actual[1..] {
+        assert_eq!(
+            parse_result.parse_token_group.tokens()[1].range,
+            i.ranges[0]
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{Range, Token, TokenGroup, TokenType};
+
+/// One lowered operation together with the source ranges of every token folded into it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instruction {
+    pub op: Op,
+    pub ranges: Vec<Range>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Op {
+    /// Add to the current cell, a run of `+` and `-` folded into one value.
+    Add(i32),
+    /// Move the pointer, a run of `>` and `<` folded into one offset.
+    Move(isize),
+    /// Repeat the body while the current cell is not zero.
+    Loop(Vec<Instruction>),
+    Output,
+    Input,
+    /// Clear the current cell, recognized from `[-]`.
+    SetZero,
+    /// Add the current cell multiplied by the factor to the cell at the offset, recognized
+    /// from balanced loops like `[->+<]`. Does nothing when the current cell is zero.
+    MulAdd(isize, i32),
+    /// Move right until the current cell is zero, recognized from `[>]`.
+    ScanRight,
+    /// Move left until the current cell is zero, recognized from `[<]`.
+    ScanLeft,
+}
+
+/// Lower a parsed token tree into run-length folded operations.
+///
+/// Comments do not break a run, and runs cancelling out to zero are dropped. `>` and `<` only fold
+/// while they move the same way: in `<>` or `><` the first move can fail at either end of the tape.
+pub fn lower(token_group: &TokenGroup) -> Vec<Instruction> {
+    lower_with(token_group, true)
+}
+
+/// `lower`, folding `+` and `-` into one run only when `cells_wrap`. Cells that do not wrap fail on
+/// the first command of `-+` or `+-` going past zero or the maximum, which the folded run would hide.
+pub fn lower_with(token_group: &TokenGroup, cells_wrap: bool) -> Vec<Instruction> {
+    let mut result = Vec::new();
+    for token in token_group.tokens() {
+        match &token.token_type {
+            TokenType::Increment => fold_add(&mut result, token, 1, cells_wrap),
+            TokenType::Decrement => fold_add(&mut result, token, -1, cells_wrap),
+            TokenType::PointerIncrement => fold_move(&mut result, token, 1),
+            TokenType::PointerDecrement => fold_move(&mut result, token, -1),
+            TokenType::Output => push_or_drop_last(&mut result, Op::Output, token.range),
+            TokenType::Input => push_or_drop_last(&mut result, Op::Input, token.range),
+            TokenType::SubGroup(sg) => push_or_drop_last(
+                &mut result,
+                Op::Loop(lower_with(sg, cells_wrap)),
+                token.range,
+            ),
+            _ => (),
+        }
+    }
+    drop_empty_last(&mut result);
+    result
+}
+
+fn fold_add(result: &mut Vec<Instruction>, token: &Token, value: i32, cells_wrap: bool) {
+    match result.last_mut() {
+        Some(Instruction {
+            op: Op::Add(n),
+            ranges,
+        }) if cells_wrap || n.signum() == value.signum() => {
+            *n = n.wrapping_add(value);
+            ranges.push(token.range);
+        }
+        _ => push_or_drop_last(result, Op::Add(value), token.range),
+    }
+}
+
+fn fold_move(result: &mut Vec<Instruction>, token: &Token, value: isize) {
+    match result.last_mut() {
+        Some(Instruction {
+            op: Op::Move(n),
+            ranges,
+        }) if n.signum() == value.signum() => {
+            *n += value;
+            ranges.push(token.range);
+        }
+        _ => push_or_drop_last(result, Op::Move(value), token.range),
+    }
+}
+
+fn push_or_drop_last(result: &mut Vec<Instruction>, op: Op, range: Range) {
+    drop_empty_last(result);
+    result.push(Instruction {
+        op,
+        ranges: vec![range],
+    });
+}
+
+// A finished run such as `+-` or `><` does nothing, remove it before starting the next one.
+fn drop_empty_last(result: &mut Vec<Instruction>) {
+    if matches!(
+        result.last(),
+        Some(Instruction {
+            op: Op::Add(0) | Op::Move(0),
+            ..
+        })
+    ) {
+        result.pop();
+    }
+}
+
+#[test]
+fn lower_should_fold_runs() {
+    let parse_result = crate::parse("+++>>-<.,[->+<]").unwrap();
+    let actual = lower(&parse_result.parse_token_group);
+
+    let ops: Vec<&Op> = actual.iter().map(|i| &i.op).collect();
+    assert_eq!(7, ops.len());
+    assert_eq!(&Op::Add(3), ops[0]);
+    assert_eq!(&Op::Move(2), ops[1]);
+    assert_eq!(&Op::Add(-1), ops[2]);
+    assert_eq!(&Op::Move(-1), ops[3]);
+    assert_eq!(&Op::Output, ops[4]);
+    assert_eq!(&Op::Input, ops[5]);
+    match ops[6] {
+        Op::Loop(body) => {
+            let body_ops: Vec<&Op> = body.iter().map(|i| &i.op).collect();
+            assert_eq!(
+                vec![&Op::Add(-1), &Op::Move(1), &Op::Add(1), &Op::Move(-1)],
+                body_ops
+            );
+        }
+        _ => panic!("expected a loop"),
+    }
+}
+
+#[test]
+fn lower_should_keep_source_ranges() {
+    let parse_result = crate::parse("++//comment\n+\n[-]").unwrap();
+    let actual = lower(&parse_result.parse_token_group);
+
+    assert_eq!(2, actual.len());
+    assert_eq!(Op::Add(3), actual[0].op);
+    assert_eq!(3, actual[0].ranges.len());
+    assert_eq!(crate::Position::new(1, 0), actual[0].ranges[2].start);
+    assert_eq!(crate::Position::new(2, 0), actual[1].ranges[0].start);
+    assert_eq!(crate::Position::new(2, 3), actual[1].ranges[0].end);
+}
+
+#[test]
+fn lower_should_drop_cancelled_runs() {
+    let parse_result = crate::parse("+-.-+").unwrap();
+    let actual = lower(&parse_result.parse_token_group);
+
+    assert_eq!(1, actual.len());
+    assert_eq!(Op::Output, actual[0].op);
+}
+
+#[test]
+fn lower_with_should_keep_turning_adds_without_wrapping() {
+    let parse_result = crate::parse("++-[-+]").unwrap();
+    let actual = lower_with(&parse_result.parse_token_group, false);
+
+    assert_eq!(Op::Add(2), actual[0].op);
+    assert_eq!(Op::Add(-1), actual[1].op);
+    match &actual[2].op {
+        Op::Loop(body) => {
+            let body_ops: Vec<&Op> = body.iter().map(|i| &i.op).collect();
+            assert_eq!(vec![&Op::Add(-1), &Op::Add(1)], body_ops);
+        }
+        _ => panic!("expected a loop"),
+    }
+    assert_eq!(Op::Add(1), lower(&parse_result.parse_token_group)[0].op);
+}
+
+#[test]
+fn lower_should_not_fold_moves_turning_around() {
+    let parse_result = crate::parse(">><<<>").unwrap();
+    let actual = lower(&parse_result.parse_token_group);
+
+    let ops: Vec<&Op> = actual.iter().map(|i| &i.op).collect();
+    assert_eq!(vec![&Op::Move(2), &Op::Move(-3), &Op::Move(1)], ops);
+    assert_eq!(3, actual[1].ranges.len());
+}
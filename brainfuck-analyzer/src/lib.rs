@@ -1,2 +1,6 @@
 mod analyzer;
+mod idiom;
+mod ir;
 pub use analyzer::*;
+pub use idiom::*;
+pub use ir::*;
@@ -28,7 +28,9 @@ impl<'a, TUserData> DapService<'a, TUserData> {
     pub fn register<TArguments: DeserializeOwned + 'a, TResponseBody: Serialize + 'a>(
         mut self,
         fn_name: String,
-        fn_handler: Box<dyn Fn(&mut TUserData, Option<TArguments>) -> Result<TResponseBody, String>>,
+        fn_handler: Box<
+            dyn Fn(&mut TUserData, Option<TArguments>) -> Result<TResponseBody, String>,
+        >,
     ) -> Self {
         self.dealer.register(fn_name, fn_handler);
         self
@@ -285,7 +287,9 @@ impl<'a, TUserData> Dealer<'a, TUserData> {
     pub fn register<TArguments: DeserializeOwned + 'a, TResponseBody: Serialize + 'a>(
         &mut self,
         fn_name: String,
-        fn_handler: Box<dyn Fn(&mut TUserData, Option<TArguments>) -> Result<TResponseBody, String>>,
+        fn_handler: Box<
+            dyn Fn(&mut TUserData, Option<TArguments>) -> Result<TResponseBody, String>,
+        >,
     ) {
         let new_function = move |user_data: &mut TUserData, request_str: String| {
             let request_with_arg: DAPRequestWithArguments<TArguments> =
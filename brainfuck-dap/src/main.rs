@@ -9,7 +9,7 @@ use std::{
 use base64::engine::general_purpose::STANDARD_NO_PAD as base64_encoder;
 use base64::Engine as _;
 use brainfuck_interpreter::{
-    BrainfuckDebugInterpreter, OutputCategoryEnum, Position, StoppedReasonEnum,
+    BrainfuckDebugInterpreter, Dialect, OutputCategoryEnum, Position, StoppedReasonEnum,
 };
 use dap::{DapService, EventPoster};
 use serde::{Deserialize, Serialize};
@@ -106,15 +106,17 @@ impl<'a> UserData<'a> {
     ) -> Result<(), String> {
         info!(">> brainfuck-dap/main launch function");
         let mut event_poster = self.event_poster.clone();
+        let launch_request_args = launch_request_args.ok_or("Missing launch arguments.")?;
+        let dialect = launch_request_args.dialect()?;
 
         if let Ok(mut current_runtime_lock) = self.runtime.lock() {
             match *current_runtime_lock {
                 RunningState::Idle => {
-                    let mut brainfuck_debug_interpreter = BrainfuckDebugInterpreter::from_file(
-                        &launch_request_args.unwrap().program,
-                    )?;
+                    let mut brainfuck_debug_interpreter =
+                        BrainfuckDebugInterpreter::from_file(&launch_request_args.program)?;
 
                     brainfuck_debug_interpreter.clear_breakpoints();
+                    brainfuck_debug_interpreter.set_dialect(dialect);
                     info!("brainfuck_debug_interpreter init completed.");
 
                     *current_runtime_lock =
@@ -127,12 +129,13 @@ impl<'a> UserData<'a> {
                     self.state_error(&mut event_poster, "Running", "launch")
                 }
                 RunningState::Terminated(_) => {
-                    let source_content = fs::read_to_string(launch_request_args.unwrap().program)
+                    let source_content = fs::read_to_string(launch_request_args.program)
                         .expect("Should have been able to read the file");
                     let mut brainfuck_debug_interpreter =
                         BrainfuckDebugInterpreter::new(source_content);
 
                     brainfuck_debug_interpreter.clear_breakpoints();
+                    brainfuck_debug_interpreter.set_dialect(dialect);
                     info!("brainfuck_debug_interpreter init completed.");
 
                     *current_runtime_lock =
@@ -748,6 +751,26 @@ impl Event<MemoryEventBody> {
 #[serde(rename_all = "camelCase")]
 struct LaunchRequestArguments {
     program: String,
+    cell_width: Option<String>,
+    overflow: Option<String>,
+    tape: Option<String>,
+}
+
+impl LaunchRequestArguments {
+    // unset values keep the default dialect
+    fn dialect(&self) -> Result<Dialect, String> {
+        let mut dialect = Dialect::default();
+        if let Some(cell_width) = &self.cell_width {
+            dialect.cell_width = cell_width.parse()?;
+        }
+        if let Some(overflow) = &self.overflow {
+            dialect.overflow = overflow.parse()?;
+        }
+        if let Some(tape) = &self.tape {
+            dialect.tape = tape.parse()?;
+        }
+        Ok(dialect)
+    }
 }
 /* ----------------- configuration_done ----------------- */
 #[derive(Deserialize)]
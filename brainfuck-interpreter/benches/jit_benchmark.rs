@@ -1,34 +1,89 @@
 use brainfuck_interpreter::{
-    autojit::interpret_auto_jit, interpreter::interpret, jit::interpret_jit,
+    AutoJITEngine, Dialect, Engine, InterpreterEngine, JITEngine, VMEngine,
 };
 use criterion::{criterion_group, criterion_main, Criterion};
-use std::fs;
+use std::{fs, io};
+
+// prepare and run a fresh engine, like the command line does
+fn run_engine(mut engine: impl Engine, contents: &str) {
+    engine.prepare(contents).unwrap();
+    engine.run(&mut io::empty(), &mut io::sink()).unwrap();
+}
 
 pub fn criterion_benchmark_calculation(c: &mut Criterion) {
     let url = "./benches/jit_benchmark_test_calculation.bf".to_string();
     let contents = fs::read_to_string(url).expect("Should have been able to read the file");
 
-    c.bench_function("test_with_jit_c", |b| b.iter(|| interpret_jit(&contents)));
+    c.bench_function("test_with_jit_c", |b| {
+        b.iter(|| run_engine(JITEngine::new(Dialect::default()), &contents))
+    });
     c.bench_function("test_with_autojit_c", |b| {
-        b.iter(|| interpret_auto_jit(&contents))
+        b.iter(|| run_engine(AutoJITEngine::new(Dialect::default()), &contents))
+    });
+    c.bench_function("test_without_jit_c", |b| {
+        b.iter(|| run_engine(InterpreterEngine::new(Dialect::default()), &contents))
+    });
+    c.bench_function("test_with_vm_c", |b| {
+        b.iter(|| run_engine(VMEngine::new(Dialect::default()), &contents))
     });
-    c.bench_function("test_without_jit_c", |b| b.iter(|| interpret(&contents)));
 }
 
 pub fn criterion_benchmark_output(c: &mut Criterion) {
     let url = "./benches/jit_benchmark_test_output.bf".to_string();
     let contents = fs::read_to_string(url).expect("Should have been able to read the file");
 
-    c.bench_function("test_with_jit_o", |b| b.iter(|| interpret_jit(&contents)));
+    c.bench_function("test_with_jit_o", |b| {
+        b.iter(|| run_engine(JITEngine::new(Dialect::default()), &contents))
+    });
     c.bench_function("test_with_autojit_o", |b| {
-        b.iter(|| interpret_auto_jit(&contents))
+        b.iter(|| run_engine(AutoJITEngine::new(Dialect::default()), &contents))
+    });
+    c.bench_function("test_without_jit_o", |b| {
+        b.iter(|| run_engine(InterpreterEngine::new(Dialect::default()), &contents))
+    });
+    c.bench_function("test_with_vm_o", |b| {
+        b.iter(|| run_engine(VMEngine::new(Dialect::default()), &contents))
+    });
+}
+
+// long runs of `>` and `<` inside loops, each run is one move with one bounds check in the JIT
+pub fn criterion_benchmark_moves(c: &mut Criterion) {
+    let url = "./benches/jit_benchmark_test_moves.bf".to_string();
+    let contents = fs::read_to_string(url).expect("Should have been able to read the file");
+
+    c.bench_function("test_with_jit_m", |b| {
+        b.iter(|| run_engine(JITEngine::new(Dialect::default()), &contents))
+    });
+    c.bench_function("test_with_autojit_m", |b| {
+        b.iter(|| run_engine(AutoJITEngine::new(Dialect::default()), &contents))
+    });
+    c.bench_function("test_without_jit_m", |b| {
+        b.iter(|| run_engine(InterpreterEngine::new(Dialect::default()), &contents))
+    });
+    c.bench_function("test_with_vm_m", |b| {
+        b.iter(|| run_engine(VMEngine::new(Dialect::default()), &contents))
+    });
+}
+
+// compiling alone, which grows with the size of the generated code
+pub fn criterion_benchmark_compile(c: &mut Criterion) {
+    let url = "./benches/jit_benchmark_test_calculation.bf".to_string();
+    let contents = fs::read_to_string(url).expect("Should have been able to read the file");
+
+    c.bench_function("test_jit_prepare_c", |b| {
+        b.iter(|| {
+            JITEngine::new(Dialect::default())
+                .prepare(&contents)
+                .unwrap()
+        })
     });
-    c.bench_function("test_without_jit_o", |b| b.iter(|| interpret(&contents)));
 }
 
 criterion_group!(
     benches,
     criterion_benchmark_calculation,
-    criterion_benchmark_output
+    criterion_benchmark_output,
+    criterion_benchmark_moves,
+    criterion_benchmark_compile
 );
 criterion_main!(benches);
@@ -0,0 +1,126 @@
+use std::fmt::Write;
+
+use crate::dialect::Dialect;
+use crate::jit::compile;
+use brainfuck_analyzer::{parse, ParseError, Range};
+use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter};
+
+// Source text shown after the position of a block, longer ranges are cut.
+const MAX_SNIPPET_CHARS: usize = 40;
+
+/// Compiles the program like the JIT engine does and lists the generated x86-64 code.
+///
+/// Every block of instructions is headed by the line:column of the source it was compiled from and
+/// that source, every instruction shows its offset from the start of the code and its raw bytes.
+/// Offsets of jump targets are relative to the start as well.
+pub fn emit_asm(source: &str, dialect: Dialect) -> Result<String, ParseError> {
+    let parse_result = parse(source)?;
+    let jit_cache = compile(&parse_result.parse_token_group, dialect);
+    let code = jit_cache.code();
+    let blocks = jit_cache.code_blocks();
+
+    let mut formatter = IntelFormatter::new();
+    formatter.options_mut().set_hex_prefix("0x");
+    formatter.options_mut().set_hex_suffix("");
+    formatter.options_mut().set_uppercase_hex(false);
+    formatter.options_mut().set_branch_leading_zeros(false);
+    let mut decoder = Decoder::with_ip(64, code, 0, DecoderOptions::NONE);
+    let mut instruction = Instruction::default();
+    let mut next_block = 0;
+    let mut listing = String::new();
+    let mut text = String::new();
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instruction);
+        let offset = instruction.ip() as usize;
+        while next_block < blocks.len() && blocks[next_block].0 <= offset {
+            match blocks[next_block].1 {
+                Some(range) => writeln!(
+                    listing,
+                    "; {}:{} {}",
+                    range.start.line + 1,
+                    range.start.character + 1,
+                    snippet(source, range)
+                ),
+                None => writeln!(listing, "; exit"),
+            }
+            .unwrap();
+            next_block += 1;
+        }
+
+        text.clear();
+        formatter.format(&instruction, &mut text);
+        let bytes = code[offset..offset + instruction.len()]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(listing, "{:04x}  {:<30}  {}", offset, bytes, text).unwrap();
+    }
+    Ok(listing)
+}
+
+// The source within `range`, up to the end of its first line.
+fn snippet(source: &str, range: Range) -> String {
+    let line = source.lines().nth(range.start.line as usize).unwrap_or("");
+    let start = range.start.character as usize;
+    let end = if range.end.line == range.start.line {
+        range.end.character as usize
+    } else {
+        line.chars().count()
+    };
+    let text: String = line
+        .chars()
+        .skip(start)
+        .take(end.saturating_sub(start))
+        .collect();
+    if text.chars().count() > MAX_SNIPPET_CHARS || range.end.line != range.start.line {
+        let cut: String = text.chars().take(MAX_SNIPPET_CHARS).collect();
+        format!("{}...", cut)
+    } else {
+        text
+    }
+}
+
+#[test]
+fn test_emit_asm() {
+    let source = "++\n[>.<-]>.";
+    let listing = emit_asm(source, Dialect::default()).unwrap();
+
+    let headers: Vec<&str> = listing
+        .lines()
+        .filter(|line| line.starts_with(';'))
+        .collect();
+    assert_eq!(
+        vec![
+            "; 1:1 ++",
+            "; 2:1 [>.<-]",
+            "; 2:2 >.<-",
+            "; 2:2 >",
+            "; 2:3 .",
+            "; 2:4 <",
+            "; 2:5 -",
+            "; 2:2 >",
+            "; 2:3 .",
+            "; 2:4 <",
+            "; 2:5 -",
+            "; 2:6 ]",
+            "; 2:7 >",
+            "; 2:8 .",
+            "; exit"
+        ],
+        headers
+    );
+    assert!(listing.contains("add byte ptr [rdi+rsi],2"));
+    assert!(listing.lines().last().unwrap().ends_with("ret"));
+
+    // the byte columns put together are the whole code
+    let parse_result = parse(source).unwrap();
+    let jit_cache = compile(&parse_result.parse_token_group, Dialect::default());
+    let bytes: Vec<u8> = listing
+        .lines()
+        .filter(|line| !line.starts_with(';'))
+        .flat_map(|line| line[6..36].split_whitespace())
+        .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+        .collect();
+    assert_eq!(jit_cache.code(), bytes);
+}
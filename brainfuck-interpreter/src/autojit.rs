@@ -1,168 +1,428 @@
 use std::{
-    io::Read,
+    collections::BTreeMap,
+    fmt,
+    io::{Read, Write},
     sync::mpsc::{self, Receiver, Sender},
     thread,
+    time::{Duration, Instant},
 };
 
-use crate::jit::{compile, run, IBrainfuckRuntime, JITCache};
-use brainfuck_analyzer::{parse, Range, Token, TokenGroup, TokenType};
+use crate::dialect::Dialect;
+use crate::engine::{Engine, Limits, RunContext};
+use crate::error::{ExecutionError, RuntimeError};
+use crate::jit::{compile, run, JITCache};
+use crate::tape::Tape;
+use brainfuck_analyzer::{parse, ParseError, Position, Range, Token, TokenGroup, TokenType};
+use serde_json::json;
 
-struct SubGroupCache {
-    range: Range,
-    jit_cache: Option<JITCache>,
-    hit_count: usize,
+/// When the auto-JIT engine compiles a loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TieringPolicy {
+    /// Iterations a loop is interpreted before it is sent to the compiler.
+    pub threshold: usize,
+    /// Loops waiting for the compiler at most. Further hot loops stay interpreted until there is room.
+    pub max_queue: usize,
+    /// Also compile the loop around a hot loop once it starts its next iteration, so whole loop nests
+    /// end up in compiled code instead of just the innermost loops.
+    pub promote_outer: bool,
 }
 
-pub struct AutoJITBrainfuckMemory {
-    pub index: usize,
-    pub memory: Vec<u8>,
+impl Default for TieringPolicy {
+    fn default() -> TieringPolicy {
+        TieringPolicy {
+            threshold: 3,
+            max_queue: 4,
+            promote_outer: true,
+        }
+    }
 }
 
-impl IBrainfuckRuntime for AutoJITBrainfuckMemory {
-    fn get_memory_vec_ptr(&self) -> *const u8 {
-        &self.memory[0] as *const u8
+/// A loop the auto-JIT engine sent to the compiler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledLoop {
+    /// Source of the loop, brackets included.
+    pub range: Range,
+    /// Iterations it was interpreted until then.
+    pub iterations: usize,
+    /// Sent because a loop inside it was, before reaching the threshold itself.
+    pub promoted: bool,
+    /// Steps the program had taken when the loop was sent.
+    pub queued_at_step: u64,
+    /// Steps the program had taken when the compiled code was picked up, `None` when it never was.
+    pub ready_at_step: Option<u64>,
+}
+
+impl fmt::Display for CompiledLoop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "loop at {}:{} queued after {} iterations at step {}",
+            self.range.start.line + 1,
+            self.range.start.character + 1,
+            self.iterations,
+            self.queued_at_step
+        )?;
+        if self.promoted {
+            write!(f, " (promoted)")?;
+        }
+        match self.ready_at_step {
+            Some(step) => write!(f, ", compiled code used from step {}", step),
+            None => write!(f, ", compiled code never used"),
+        }
     }
+}
+
+/// What the auto-JIT engine did with one loop during a run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopStats {
+    /// Source of the loop, brackets included.
+    pub range: Range,
+    pub interpreted_iterations: u64,
+    /// Iterations the engine ran in the loop's compiled code. Iterations run by the compiled code of
+    /// a loop around it are not counted.
+    pub compiled_iterations: u64,
+    /// Whether the loop was sent to the compiler.
+    pub queued: bool,
+    /// Time the compiler spent on the loop, `None` when it did not finish before the run did.
+    pub compile_time: Option<Duration>,
+}
 
-    fn get_index(&self) -> usize {
-        self.index
+impl LoopStats {
+    /// The loop was sent to the compiler, but its code never ran.
+    pub fn arrived_too_late(&self) -> bool {
+        self.queued && self.compiled_iterations == 0
     }
+}
 
-    fn set_index(&mut self, new_index: usize) {
-        self.index = new_index;
+impl fmt::Display for LoopStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "loop at {}:{}: {} iterations interpreted, {} compiled",
+            self.range.start.line + 1,
+            self.range.start.character + 1,
+            self.interpreted_iterations,
+            self.compiled_iterations
+        )?;
+        if let Some(compile_time) = self.compile_time {
+            write!(f, ", compiled in {:?}", compile_time)?;
+        }
+        if self.arrived_too_late() {
+            write!(f, ", compiled code arrived too late")?;
+        }
+        Ok(())
     }
 }
 
-impl AutoJITBrainfuckMemory {
-    pub fn new() -> AutoJITBrainfuckMemory {
-        AutoJITBrainfuckMemory {
-            index: 0,
-            memory: vec![0; 1000],
+/// Interprets the program and compiles hot loops in a background thread, see `TieringPolicy`.
+pub struct AutoJITEngine {
+    token_group: TokenGroup,
+    tape: Tape,
+    limits: Limits,
+    policy: TieringPolicy,
+    compiled_loops: Vec<CompiledLoop>,
+    loop_stats: Vec<LoopStats>,
+}
+
+impl AutoJITEngine {
+    pub fn new(dialect: Dialect) -> AutoJITEngine {
+        AutoJITEngine {
+            token_group: TokenGroup::default(),
+            tape: Tape::new(dialect),
+            limits: Limits::default(),
+            policy: TieringPolicy::default(),
+            compiled_loops: Vec::new(),
+            loop_stats: Vec::new(),
         }
     }
 
-    fn interpret_token(
-        &mut self,
-        token: &Token,
-        sub_group_cache_stack: &mut Vec<SubGroupCache>,
-        m2j_tx: &Sender<(Range, TokenGroup)>,
-        j2m_tx: &Receiver<(Range, JITCache)>,
-    ) {
-        match &token.token_type {
-            TokenType::PointerIncrement => {
-                if self.memory.len() - self.index == 1 {
-                    self.memory.resize(self.memory.len() * 2, 0);
-                }
-                self.index += 1;
-            }
-            TokenType::PointerDecrement => {
-                if self.index == 0 {
-                    panic!("Cannot decrease pointer when pointer index = 0.");
-                }
-                self.index -= 1;
-            }
-            TokenType::Increment => {
-                if self.memory[self.index] == u8::MAX {
-                    self.memory[self.index] = u8::MIN;
-                } else {
-                    self.memory[self.index] += 1;
-                }
-            }
-            TokenType::Decrement => {
-                if self.memory[self.index] == u8::MIN {
-                    self.memory[self.index] = u8::MAX;
-                } else {
-                    self.memory[self.index] -= 1;
-                }
-            }
-            TokenType::Output => {
-                let c: char = self.memory[self.index].into();
-                print!("{}", c);
-            }
-            TokenType::Input => {
-                self.memory[self.index] = std::io::stdin().bytes().next().unwrap().unwrap();
-            }
-            TokenType::SubGroup(sg) => {
-                let mut need_pop = false;
-                while self.memory[self.index] != 0 {
-                    need_pop = true;
-
-                    // try to receive jit analysis result and update cache
-                    while let Result::Ok((jit_range, jit_cache)) = j2m_tx.try_recv() {
-                        for current_item in sub_group_cache_stack.iter_mut() {
-                            if (current_item.range).eq(&jit_range) {
-                                current_item.jit_cache = Some(jit_cache);
-                                break;
-                            }
-                        }
-                    }
+    pub fn set_tiering_policy(&mut self, policy: TieringPolicy) {
+        self.policy = policy;
+    }
 
-                    // update stack
-                    if sub_group_cache_stack.is_empty()
-                        || (sub_group_cache_stack.last().unwrap().range != token.range)
-                    {
-                        sub_group_cache_stack.push(SubGroupCache {
-                            range: token.range,
-                            jit_cache: None,
-                            hit_count: 1,
-                        });
-                    } else {
-                        sub_group_cache_stack.last_mut().unwrap().hit_count += 1;
-                    }
+    /// The loops the last run sent to the compiler, in order.
+    pub fn compiled_loops(&self) -> &[CompiledLoop] {
+        &self.compiled_loops
+    }
 
-                    if let Some(jit_cache) = &sub_group_cache_stack.last().unwrap().jit_cache {
-                        run(jit_cache, self);
-                    } else {
-                        if sub_group_cache_stack.last().unwrap().hit_count == 3 {
-                            m2j_tx.send((token.range, *sg.clone())).unwrap();
-                        }
+    /// Every loop the last run entered, in source order.
+    pub fn loop_stats(&self) -> &[LoopStats] {
+        &self.loop_stats
+    }
 
-                        for token in sg.tokens().into_iter() {
-                            self.interpret_token(token, sub_group_cache_stack, m2j_tx, j2m_tx);
-                        }
-                    }
-                }
+    /// `loop_stats` and `compiled_loops` as JSON, positions count from zero.
+    pub fn stats_json(&self) -> String {
+        let range = |range: Range| {
+            json!({
+                "start": { "line": range.start.line, "character": range.start.character },
+                "end": { "line": range.end.line, "character": range.end.character },
+            })
+        };
+        let loops: Vec<_> = self
+            .loop_stats
+            .iter()
+            .map(|stats| {
+                json!({
+                    "range": range(stats.range),
+                    "interpreted_iterations": stats.interpreted_iterations,
+                    "compiled_iterations": stats.compiled_iterations,
+                    "queued": stats.queued,
+                    "compile_seconds": stats.compile_time.map(|time| time.as_secs_f64()),
+                    "arrived_too_late": stats.arrived_too_late(),
+                })
+            })
+            .collect();
+        let compiled: Vec<_> = self
+            .compiled_loops
+            .iter()
+            .map(|compiled_loop| {
+                json!({
+                    "range": range(compiled_loop.range),
+                    "iterations": compiled_loop.iterations,
+                    "promoted": compiled_loop.promoted,
+                    "queued_at_step": compiled_loop.queued_at_step,
+                    "ready_at_step": compiled_loop.ready_at_step,
+                })
+            })
+            .collect();
+        serde_json::to_string_pretty(&json!({ "loops": loops, "compiled": compiled })).unwrap()
+    }
+}
 
-                // clear sub group from stack
-                if need_pop {
-                    sub_group_cache_stack.truncate(sub_group_cache_stack.len() - 1);
-                }
-            }
-            _ => (),
-        }
+impl Engine for AutoJITEngine {
+    fn prepare(&mut self, source: &str) -> Result<(), ParseError> {
+        let parse_result = parse(source)?;
+        self.token_group = parse_result.parse_token_group;
+        self.tape = Tape::new(self.tape.dialect);
+        Ok(())
+    }
+
+    fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), RuntimeError> {
+        let mut context = RunContext::new(&mut self.tape, input, output, &self.limits);
+        let mut tiering = Tiering::start(self.policy, context.tape.dialect);
+        let result = self
+            .token_group
+            .tokens()
+            .iter()
+            .try_for_each(|token| interpret_token(&mut context, token, &mut tiering));
+        context.flush_output();
+        self.loop_stats = tiering.loop_stats();
+        self.compiled_loops = tiering.report;
+        result
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    fn tape_mut(&mut self) -> &mut Tape {
+        &mut self.tape
     }
 }
 
-pub fn interpret_auto_jit(input: &str) {
-    let parse_result = parse(input).unwrap();
-    let token_group = parse_result.parse_token_group;
+// What a run learns about its loops and the compiler thread working for it.
+struct Tiering {
+    policy: TieringPolicy,
+    // keyed by the start of the loop, kept after the loop exits so its code is reused next time
+    loops: BTreeMap<Position, LoopState>,
+    // loops currently iterating, innermost last
+    running: Vec<Position>,
+    // loops sent to the compiler whose code has not come back yet
+    in_flight: usize,
+    report: Vec<CompiledLoop>,
+    m2j_tx: Sender<(Range, TokenGroup)>,
+    j2m_rx: Receiver<(Range, JITCache, Duration)>,
+}
 
-    let mut memory = AutoJITBrainfuckMemory::new();
-    main_thread(&mut memory, &token_group);
+#[derive(Default)]
+struct LoopState {
+    range: Range,
+    // interpreted iterations
+    iterations: usize,
+    compiled_iterations: u64,
+    compile_time: Option<Duration>,
+    // a loop inside this one was sent to the compiler
+    hot_inner: bool,
+    queued: bool,
+    jit_cache: Option<JITCache>,
+    // entry of the loop in the report
+    report_index: usize,
 }
 
-fn main_thread(memory: &mut AutoJITBrainfuckMemory, token_group: &TokenGroup) {
-    let (m2j_tx, m2j_rx) = mpsc::channel();
-    let (j2m_tx, j2m_rx) = mpsc::channel();
+impl Tiering {
+    fn start(policy: TieringPolicy, dialect: Dialect) -> Tiering {
+        let (m2j_tx, m2j_rx) = mpsc::channel();
+        let (j2m_tx, j2m_rx) = mpsc::channel();
+        thread::spawn(move || {
+            jit_thread(m2j_rx, j2m_tx, dialect);
+        });
+        Tiering {
+            policy,
+            loops: BTreeMap::new(),
+            running: Vec::new(),
+            in_flight: 0,
+            report: Vec::new(),
+            m2j_tx,
+            j2m_rx,
+        }
+    }
+
+    // Take the code the compiler finished so far.
+    fn receive(&mut self, step: u64) {
+        while let Ok((range, jit_cache, compile_time)) = self.j2m_rx.try_recv() {
+            self.in_flight -= 1;
+            let state = self.loops.get_mut(&range.start).unwrap();
+            self.report[state.report_index].ready_at_step = Some(step);
+            state.jit_cache = Some(jit_cache);
+            state.compile_time = Some(compile_time);
+        }
+    }
+
+    // Statistics of every loop entered. Code the compiler finished after the run only adds its
+    // compile time.
+    fn loop_stats(&mut self) -> Vec<LoopStats> {
+        while let Ok((range, _, compile_time)) = self.j2m_rx.try_recv() {
+            self.loops.get_mut(&range.start).unwrap().compile_time = Some(compile_time);
+        }
+        self.loops
+            .values()
+            .map(|state| LoopStats {
+                range: state.range,
+                interpreted_iterations: state.iterations as u64,
+                compiled_iterations: state.compiled_iterations,
+                queued: state.queued,
+                compile_time: state.compile_time,
+            })
+            .collect()
+    }
+
+    // Count an interpreted iteration of the innermost running loop and send it to the compiler
+    // when the policy finds it hot.
+    fn interpreted_iteration(&mut self, range: Range, body: &TokenGroup, step: u64) {
+        let outer = self.running.iter().rev().nth(1).copied();
+        let state = self.loops.entry(range.start).or_default();
+        state.range = range;
+        state.iterations += 1;
+        let reached_threshold = state.iterations >= self.policy.threshold;
+        let promoted = self.policy.promote_outer && state.hot_inner && !reached_threshold;
+        if state.queued
+            || !(reached_threshold || promoted)
+            || self.in_flight >= self.policy.max_queue
+        {
+            return;
+        }
+
+        state.queued = true;
+        state.report_index = self.report.len();
+        self.report.push(CompiledLoop {
+            range,
+            iterations: state.iterations,
+            promoted,
+            queued_at_step: step,
+            ready_at_step: None,
+        });
+        self.in_flight += 1;
+        self.m2j_tx.send((range, body.clone())).unwrap();
+        if let Some(outer) = outer {
+            self.loops.entry(outer).or_default().hot_inner = true;
+        }
+    }
+}
 
-    thread::spawn(move || {
-        jit_thread(m2j_rx, j2m_tx);
-    });
+fn interpret_token(
+    context: &mut RunContext,
+    token: &Token,
+    tiering: &mut Tiering,
+) -> Result<(), RuntimeError> {
+    let error = |kind| RuntimeError {
+        kind,
+        range: token.range,
+    };
+    if !is_command(token) {
+        return Ok(());
+    }
+    context.step(1).map_err(error)?;
+    let tape = &mut *context.tape;
+    match &token.token_type {
+        TokenType::PointerIncrement => tape.index = tape.offset_index(1).map_err(error)?,
+        TokenType::PointerDecrement => tape.index = tape.offset_index(-1).map_err(error)?,
+        TokenType::Increment => tape.add_cell(tape.index, 1).map_err(error)?,
+        TokenType::Decrement => tape.add_cell(tape.index, -1).map_err(error)?,
+        TokenType::Output => context.output_cell(context.tape.index),
+        TokenType::Input => context.input_cell(context.tape.index).map_err(error)?,
+        TokenType::SubGroup(sg) => {
+            let start = token.range.start;
+            tiering.running.push(start);
+            while context.tape.current_cell() != 0 {
+                tiering.receive(context.steps_taken());
 
-    let mut sub_group_cache_stack = vec![];
-    for token in token_group.tokens().into_iter() {
-        memory.interpret_token(token, &mut sub_group_cache_stack, &m2j_tx, &j2m_rx);
+                match tiering.loops.get_mut(&start) {
+                    Some(LoopState {
+                        jit_cache: Some(jit_cache),
+                        compiled_iterations,
+                        ..
+                    }) => {
+                        *compiled_iterations += 1;
+                        // compiled code only counts the steps of its inner loops
+                        let commands = sg.tokens().iter().filter(|token| is_command(token));
+                        context.step(commands.count() as u64).map_err(error)?;
+                        run(jit_cache, context)?;
+                    }
+                    _ => {
+                        tiering.interpreted_iteration(token.range, sg, context.steps_taken());
+                        for token in sg.tokens().iter() {
+                            interpret_token(context, token, tiering)?;
+                        }
+                    }
+                }
+                // each further condition check is a step of its own
+                context.step(1).map_err(error)?;
+            }
+            tiering.running.pop();
+        }
+        _ => (),
     }
+    Ok(())
+}
+
+// Tokens taking a step when interpreted, comments and breakpoints are free.
+fn is_command(token: &Token) -> bool {
+    !matches!(
+        token.token_type,
+        TokenType::Comment(_) | TokenType::Breakpoint | TokenType::LoopStart | TokenType::LoopEnd
+    )
 }
 
-fn jit_thread(m2j_rx: Receiver<(Range, TokenGroup)>, j2m_tx: Sender<(Range, JITCache)>) {
+pub fn interpret_auto_jit(
+    input: &str,
+    dialect: Dialect,
+    mut reader: impl Read,
+    mut writer: impl Write,
+) -> Result<(), ExecutionError> {
+    let mut engine = AutoJITEngine::new(dialect);
+    engine.prepare(input)?;
+    engine.run(&mut reader, &mut writer)?;
+    Ok(())
+}
+
+fn jit_thread(
+    m2j_rx: Receiver<(Range, TokenGroup)>,
+    j2m_tx: Sender<(Range, JITCache, Duration)>,
+    dialect: Dialect,
+) {
     loop {
         match m2j_rx.recv() {
             Result::Err(_) => {
                 break;
             }
             Result::Ok(received) => {
-                let jit_cache = compile(&received.1);
-                if let Err(_) = j2m_tx.send((received.0, jit_cache)) {
+                let started = Instant::now();
+                let jit_cache = compile(&received.1, dialect);
+                if let Err(_) = j2m_tx.send((received.0, jit_cache, started.elapsed())) {
                     break;
                 };
             }
@@ -170,37 +430,179 @@ fn jit_thread(m2j_rx: Receiver<(Range, TokenGroup)>, j2m_tx: Sender<(Range, JITC
     }
 }
 
+#[cfg(test)]
+fn auto_jit_engine(input: &str, dialect: Dialect) -> AutoJITEngine {
+    let mut engine = AutoJITEngine::new(dialect);
+    engine.prepare(input).unwrap();
+    engine
+}
+
 #[test]
 pub fn test_auto_jit_simple() {
-    let input = ">>++<-";
-    let parse_result = parse(input).unwrap();
-    let token_group = parse_result.parse_token_group;
-
-    let mut memory = AutoJITBrainfuckMemory::new();
-    main_thread(&mut memory, &token_group);
-    assert_eq!(2, memory.memory[2]);
-    assert_eq!(u8::MAX, memory.memory[1]);
-    assert_eq!(1, memory.index);
+    let mut engine = auto_jit_engine(">>++<-", Dialect::default());
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    assert_eq!(2, engine.tape.memory[2]);
+    assert_eq!(u8::MAX, engine.tape.memory[1]);
+    assert_eq!(1, engine.tape.index);
 }
 
 #[test]
 pub fn test_auto_jit_with_loop() {
     let input = ">>+++++++++++++++++++++++++++++++++<+++++[>.<-]";
-    interpret_auto_jit(input);
-    // should find five "!" in test terminal
+    let mut output = Vec::new();
+    interpret_auto_jit(input, Dialect::default(), std::io::empty(), &mut output).unwrap();
+    assert_eq!(b"!!!!!".to_vec(), output);
 }
 
 #[test]
 pub fn test_auto_jit_with_memory_extension() {
-    let input = ">>>>++";
-    let parse_result = parse(input).unwrap();
-    let token_group = parse_result.parse_token_group;
+    let mut engine = auto_jit_engine(">>>>++", Dialect::default());
+    engine.tape.memory = vec![0; 3];
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+
+    assert_eq!(6, engine.tape.memory.len());
+    assert_eq!(4, engine.tape.index);
+    assert_eq!(2, engine.tape.memory[4]);
+}
+
+#[test]
+pub fn test_auto_jit_with_wide_cells() {
+    let dialect = Dialect {
+        cell_width: crate::CellWidth::Bits16,
+        ..Default::default()
+    };
+    let mut engine = auto_jit_engine("-[->+<]>+++", dialect);
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    assert_eq!(vec![0, 0, 2, 0], engine.tape.memory[..4].to_vec());
+    assert_eq!(1, engine.tape.index);
+}
+
+#[test]
+pub fn test_auto_jit_pointer_underflow() {
+    use crate::error::RuntimeErrorKind;
+    use brainfuck_analyzer::Position;
+
+    let mut engine = auto_jit_engine("+>\n<<", Dialect::default());
+    let error = engine.run(&mut std::io::empty(), &mut std::io::sink());
+    let range = Range::new(Position::new(1, 1), Position::new(1, 2));
+    assert_eq!(
+        Err(RuntimeError {
+            kind: RuntimeErrorKind::PointerUnderflow,
+            range
+        }),
+        error
+    );
+    assert_eq!(0, engine.tape.index);
+}
+
+#[cfg(test)]
+fn run_with_policy(source: &str, policy: TieringPolicy) -> AutoJITEngine {
+    let mut engine = auto_jit_engine(source, Dialect::default());
+    engine.set_tiering_policy(policy);
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    engine
+}
+
+#[test]
+pub fn test_auto_jit_tiering_threshold() {
+    let policy = TieringPolicy {
+        threshold: 5,
+        ..Default::default()
+    };
+    let engine = run_with_policy("++++++++++[>+<-]", policy);
+    assert_eq!(10, engine.tape.cell(1));
+    let compiled_loops = engine.compiled_loops();
+    assert_eq!(1, compiled_loops.len());
+    assert_eq!(5, compiled_loops[0].iterations);
+    assert!(!compiled_loops[0].promoted);
+    // ten adds, the loop and four iterations of four instructions and a check
+    assert_eq!(10 + 1 + 4 * 5, compiled_loops[0].queued_at_step);
+
+    // comments are no steps
+    let engine = run_with_policy("++++++++++[>+<-/* next */]", policy);
+    assert_eq!(10 + 1 + 4 * 5, engine.compiled_loops()[0].queued_at_step);
+
+    // no room in the queue, nothing is compiled
+    let policy = TieringPolicy {
+        max_queue: 0,
+        ..Default::default()
+    };
+    let engine = run_with_policy("++++++++++[>+<-]", policy);
+    assert_eq!(10, engine.tape.cell(1));
+    assert!(engine.compiled_loops().is_empty());
+}
+
+#[test]
+pub fn test_auto_jit_tiering_promotes_outer_loops() {
+    use brainfuck_analyzer::Position;
+
+    let source = "++++[>++++++++[>+<-]<-]";
+    let inner = Range::new(Position::new(0, 14), Position::new(0, 20));
+    let outer = Range::new(Position::new(0, 4), Position::new(0, 23));
+    let engine = run_with_policy(source, TieringPolicy::default());
+    assert_eq!(32, engine.tape.cell(2));
+    let compiled: Vec<_> = engine
+        .compiled_loops()
+        .iter()
+        .map(|compiled_loop| {
+            (
+                compiled_loop.range,
+                compiled_loop.iterations,
+                compiled_loop.promoted,
+            )
+        })
+        .collect();
+    // the inner loop gets hot during the first iteration of the outer one, which is promoted at its second
+    assert_eq!(vec![(inner, 3, false), (outer, 2, true)], compiled);
+
+    let policy = TieringPolicy {
+        promote_outer: false,
+        ..Default::default()
+    };
+    let engine = run_with_policy(source, policy);
+    assert_eq!(32, engine.tape.cell(2));
+    // the outer loop reaches the threshold on its own
+    assert_eq!(inner, engine.compiled_loops()[0].range);
+    assert!(engine
+        .compiled_loops()
+        .iter()
+        .all(|compiled_loop| !compiled_loop.promoted));
+}
+
+#[test]
+pub fn test_auto_jit_loop_stats() {
+    use brainfuck_analyzer::Position;
 
-    let mut memory = AutoJITBrainfuckMemory::new();
-    memory.memory = vec![0; 3];
+    let policy = TieringPolicy {
+        threshold: 5,
+        ..Default::default()
+    };
+    // the second loop ends in the iteration it is queued in
+    let engine = run_with_policy("++++++++++[>+<-]+++++[-]", policy);
+    let stats = engine.loop_stats();
+    assert_eq!(2, stats.len());
+    assert_eq!(Position::new(0, 10), stats[0].range.start);
+    assert!(stats[0].queued);
+    assert!(stats[0].interpreted_iterations >= 5);
+    assert_eq!(
+        10,
+        stats[0].interpreted_iterations + stats[0].compiled_iterations
+    );
+    assert_eq!(Position::new(0, 21), stats[1].range.start);
+    assert_eq!(5, stats[1].interpreted_iterations);
+    assert!(stats[1].arrived_too_late());
 
-    main_thread(&mut memory, &token_group);
-    assert_eq!(6, memory.memory.len());
-    assert_eq!(4, memory.index);
-    assert_eq!(2, memory.memory[4]);
+    let json: serde_json::Value = serde_json::from_str(&engine.stats_json()).unwrap();
+    assert_eq!(21, json["loops"][1]["range"]["start"]["character"]);
+    assert_eq!(true, json["loops"][1]["arrived_too_late"]);
+    assert_eq!(2, json["compiled"].as_array().unwrap().len());
+    assert_eq!(5, json["compiled"][1]["iterations"]);
 }
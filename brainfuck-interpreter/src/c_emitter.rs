@@ -0,0 +1,268 @@
+use std::fmt::Write;
+
+use crate::dialect::{CellWidth, Dialect, OverflowBehaviour, TapeGrowth};
+use crate::error::RuntimeErrorKind;
+use brainfuck_analyzer::{parse, Instruction, Op, ParseError, Range};
+
+/// Translates the program into a self-contained C file, for platforms the JIT cannot target.
+///
+/// The tape has room for `max_cells` cells on each side the dialect lets it grow to, as in the
+/// executables of `compile_elf`. Runtime errors print their message to stderr and exit with status 1.
+pub fn emit_c(source: &str, dialect: Dialect, max_cells: usize) -> Result<String, ParseError> {
+    let parse_result = parse(source)?;
+    let instructions = dialect.instructions(&parse_result.parse_token_group);
+
+    let (tape_cells, origin) = tape_layout(dialect, max_cells).ok_or_else(|| ParseError {
+        range: Range::default(),
+        error_message: format!(
+            "A tape of {} cells on each side does not fit into memory.",
+            max_cells
+        ),
+    })?;
+    let left_of_tape = match dialect.tape {
+        TapeGrowth::Right => RuntimeErrorKind::PointerUnderflow,
+        TapeGrowth::Both => RuntimeErrorKind::MemoryLimitExceeded,
+    };
+    let cell_type = match dialect.cell_width {
+        CellWidth::Bits8 => "uint8_t",
+        CellWidth::Bits16 => "uint16_t",
+        CellWidth::Bits32 => "uint32_t",
+    };
+
+    let mut code = String::new();
+    writeln!(code, "/* Generated by brainfuck-interpreter. */").unwrap();
+    writeln!(code, "#include <stddef.h>").unwrap();
+    writeln!(code, "#include <stdint.h>").unwrap();
+    writeln!(code, "#include <stdio.h>").unwrap();
+    writeln!(code, "#include <stdlib.h>").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "typedef {} cell;", cell_type).unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "#define CELL_MAX {}", dialect.cell_width.max_value()).unwrap();
+    writeln!(code, "#define TAPE_CELLS {}", tape_cells).unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "static cell tape[TAPE_CELLS];").unwrap();
+    writeln!(code, "static ptrdiff_t p = {};", origin).unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "static void fail(const char *message) {{").unwrap();
+    writeln!(code, "    fflush(stdout);").unwrap();
+    writeln!(code, "    fprintf(stderr, \"error: %s\\n\", message);").unwrap();
+    writeln!(code, "    exit(1);").unwrap();
+    writeln!(code, "}}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(
+        code,
+        "/* Index of the cell `offset` away from the current one, stops when it is off the tape. */"
+    )
+    .unwrap();
+    writeln!(code, "static ptrdiff_t at(ptrdiff_t offset) {{").unwrap();
+    writeln!(code, "    ptrdiff_t index = p + offset;").unwrap();
+    writeln!(code, "    if (index < 0) fail(\"{}\");", left_of_tape).unwrap();
+    writeln!(
+        code,
+        "    if (index >= TAPE_CELLS) fail(\"{}\");",
+        RuntimeErrorKind::MemoryLimitExceeded
+    )
+    .unwrap();
+    writeln!(code, "    return index;").unwrap();
+    writeln!(code, "}}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "static void add(ptrdiff_t index, int64_t value) {{").unwrap();
+    match dialect.overflow {
+        OverflowBehaviour::Wrap => {
+            writeln!(code, "    tape[index] = (cell)(tape[index] + value);").unwrap();
+        }
+        OverflowBehaviour::Error => {
+            writeln!(code, "    int64_t result = (int64_t)tape[index] + value;").unwrap();
+            writeln!(
+                code,
+                "    if (result < 0 || result > CELL_MAX) fail(\"{}\");",
+                RuntimeErrorKind::CellOverflow
+            )
+            .unwrap();
+            writeln!(code, "    tape[index] = (cell)result;").unwrap();
+        }
+    }
+    writeln!(code, "}}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "static void output(void) {{").unwrap();
+    writeln!(code, "    putchar((unsigned char)tape[p]);").unwrap();
+    writeln!(code, "}}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "static void input(void) {{").unwrap();
+    writeln!(code, "    int c = getchar();").unwrap();
+    writeln!(code, "    if (c != EOF) {{").unwrap();
+    writeln!(code, "        tape[p] = (cell)c;").unwrap();
+    writeln!(code, "    }} else if (ferror(stdin)) {{").unwrap();
+    writeln!(code, "        fail(\"{}\");", RuntimeErrorKind::EndOfInput).unwrap();
+    match dialect.eof_value() {
+        Ok(None) => (),
+        Ok(Some(value)) => {
+            writeln!(code, "    }} else {{").unwrap();
+            writeln!(code, "        tape[p] = {};", value).unwrap();
+        }
+        Err(kind) => {
+            writeln!(code, "    }} else {{").unwrap();
+            writeln!(code, "        fail(\"{}\");", kind).unwrap();
+        }
+    }
+    writeln!(code, "    }}").unwrap();
+    writeln!(code, "}}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "int main(void) {{").unwrap();
+    emit_instructions(&mut code, &instructions, 1);
+    writeln!(code, "    return 0;").unwrap();
+    writeln!(code, "}}").unwrap();
+    Ok(code)
+}
+
+// Number of cells and index of the starting cell, `None` when the tape takes more bytes than
+// `ptrdiff_t` can count.
+fn tape_layout(dialect: Dialect, max_cells: usize) -> Option<(usize, usize)> {
+    // the starting cell is always there
+    let cells = max_cells.max(1);
+    let (tape_cells, origin) = match dialect.tape {
+        TapeGrowth::Right => (cells, 0),
+        TapeGrowth::Both => (cells.checked_mul(2)?.checked_sub(1)?, cells - 1),
+    };
+    let tape_bytes = tape_cells.checked_mul(dialect.cell_width.bytes())?;
+    (tape_bytes <= isize::MAX as usize).then_some((tape_cells, origin))
+}
+
+fn emit_instructions(code: &mut String, instructions: &[Instruction], depth: usize) {
+    let indent = "    ".repeat(depth);
+    for instruction in instructions {
+        match &instruction.op {
+            Op::Add(n) => writeln!(code, "{}add(p, {});", indent, n),
+            Op::Move(n) => writeln!(code, "{}p = at({});", indent, n),
+            Op::Output => writeln!(code, "{}output();", indent),
+            Op::Input => writeln!(code, "{}input();", indent),
+            Op::Loop(body) => {
+                writeln!(code, "{}while (tape[p]) {{", indent).unwrap();
+                emit_instructions(code, body, depth + 1);
+                writeln!(code, "{}}}", indent)
+            }
+            Op::SetZero => writeln!(code, "{}tape[p] = 0;", indent),
+            Op::MulAdd(offset, factor) => writeln!(
+                code,
+                "{}if (tape[p]) add(at({}), (int64_t)tape[p] * {});",
+                indent, offset, factor
+            ),
+            Op::ScanRight => writeln!(code, "{}while (tape[p]) p = at(1);", indent),
+            Op::ScanLeft => writeln!(code, "{}while (tape[p]) p = at(-1);", indent),
+        }
+        .unwrap();
+    }
+}
+
+#[test]
+fn test_c_tape_layout() {
+    let both = Dialect {
+        cell_width: CellWidth::Bits32,
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    assert_eq!(Some((1, 0)), tape_layout(Dialect::default(), 0));
+    assert_eq!(Some((19, 9)), tape_layout(both, 10));
+    assert_eq!(None, tape_layout(both, usize::MAX));
+    assert_eq!(None, tape_layout(both, usize::MAX / 8 + 1));
+    assert_eq!(
+        Some((isize::MAX as usize, 0)),
+        tape_layout(Dialect::default(), isize::MAX as usize)
+    );
+    assert_eq!(
+        None,
+        tape_layout(Dialect::default(), isize::MAX as usize + 1)
+    );
+    let error = emit_c("+", both, usize::MAX / 2).unwrap_err();
+    assert!(error.error_message.contains("does not fit"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_emit_c_matches_interpreter() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    // needs a C compiler, which not every machine building this crate has
+    if Command::new("cc").arg("--version").output().is_err() {
+        eprintln!("skipping test_emit_c_matches_interpreter: no cc found");
+        return;
+    }
+
+    let directory = std::env::temp_dir().join(format!("brainfuck-c-test-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let wide = Dialect {
+        cell_width: CellWidth::Bits16,
+        eof: crate::dialect::EofBehaviour::MinusOne,
+        ..Default::default()
+    };
+    let strict = Dialect {
+        overflow: OverflowBehaviour::Error,
+        eof: crate::dialect::EofBehaviour::Error,
+        ..Default::default()
+    };
+    let both = Dialect {
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    let cases = [
+        (
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.",
+            Dialect::default(),
+            "",
+        ),
+        ("-[->+>+<<]>>[-<+>]<+.,.,.", wide, "a"),
+        (",[.,]", strict, "echo"),
+        ("+[->-<]", strict, ""),
+        ("+>\n<<", Dialect::default(), ""),
+        ("+++[-<<++>+++>]<<[->>+<<]>.<[<]+<<<.", both, ""),
+    ];
+    for (index, (source, dialect, input)) in cases.into_iter().enumerate() {
+        let mut expected = Vec::new();
+        let result =
+            crate::interpreter::interpret(source, dialect, input.as_bytes(), &mut expected);
+
+        let c_file = directory.join(format!("{}.c", index));
+        let executable = directory.join(index.to_string());
+        std::fs::write(&c_file, emit_c(source, dialect, 1000).unwrap()).unwrap();
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(&executable)
+            .arg(&c_file)
+            .status()
+            .unwrap();
+        assert!(status.success(), "cc failed on {}", source);
+
+        let mut child = Command::new(&executable)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        assert_eq!(expected, output.stdout, "output differs on {}", source);
+        match result {
+            Ok(()) => assert!(output.status.success(), "failed on {}", source),
+            Err(crate::error::ExecutionError::Runtime(error)) => {
+                assert_eq!(Some(1), output.status.code(), "did not fail on {}", source);
+                assert_eq!(
+                    format!("error: {}\n", error),
+                    String::from_utf8_lossy(&output.stderr),
+                    "error differs on {}",
+                    source
+                );
+            }
+            Err(error) => panic!("{:?}", error),
+        }
+    }
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,205 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use crate::dialect::Dialect;
+use crate::jit::{compile, load, JITCache, SourceMap, CODE_VERSION};
+use brainfuck_analyzer::{Position, Range, TokenGroup};
+
+// Start of every cache file, followed by the versions of the code and of the crate that wrote it.
+const MAGIC: &[u8] = b"bfjit\0";
+
+/// A directory of programs the JIT compiled before, so later runs of the same source skip compiling.
+///
+/// Entries are keyed by a hash of the source, the dialect and the version of the generated code, and
+/// hold all three to rule out collisions. The code is loaded without any further check, so the
+/// directory must not be writable by anyone who should not run code as the current user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeCache {
+    directory: PathBuf,
+}
+
+impl CodeCache {
+    pub fn new(directory: impl Into<PathBuf>) -> CodeCache {
+        CodeCache {
+            directory: directory.into(),
+        }
+    }
+
+    // The compiled program, loaded from the cache when it holds it and compiled and stored otherwise.
+    pub(crate) fn compile(
+        &self,
+        source: &str,
+        token_group: &TokenGroup,
+        dialect: Dialect,
+    ) -> JITCache {
+        let header = header(source, dialect);
+        let path = self
+            .directory
+            .join(format!("{:016x}.bfjit", fnv1a(&header)));
+        if let Some(jit_cache) = fs::read(&path)
+            .ok()
+            .and_then(|bytes| decode(&bytes, &header, dialect))
+        {
+            return jit_cache;
+        }
+
+        let jit_cache = compile(token_group, dialect);
+        // write beside the entry and rename, so concurrent runs never read a partial file. Failing to
+        // store only costs the next run a compilation.
+        let partial = path.with_extension(format!("{}.partial", process::id()));
+        let stored = fs::create_dir_all(&self.directory)
+            .and_then(|()| fs::write(&partial, encode(&header, &jit_cache)))
+            .and_then(|()| fs::rename(&partial, &path));
+        if stored.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        jit_cache
+    }
+}
+
+// Everything the code depends on, the start of the cache file.
+fn header(source: &str, dialect: Dialect) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    push_u32(&mut bytes, CODE_VERSION);
+    push_bytes(&mut bytes, env!("CARGO_PKG_VERSION").as_bytes());
+    push_bytes(&mut bytes, format!("{:?}", dialect).as_bytes());
+    push_bytes(&mut bytes, source.as_bytes());
+    bytes
+}
+
+fn encode(header: &[u8], jit_cache: &JITCache) -> Vec<u8> {
+    let source_map = jit_cache.source_map();
+    let mut bytes = header.to_vec();
+    push_bytes(&mut bytes, jit_cache.code());
+    push_u32(&mut bytes, source_map.sites.len() as u32);
+    for site in &source_map.sites {
+        push_range(&mut bytes, *site);
+    }
+    push_u32(&mut bytes, source_map.blocks.len() as u32);
+    for (offset, range) in &source_map.blocks {
+        push_u32(&mut bytes, *offset as u32);
+        match range {
+            Some(range) => {
+                bytes.push(1);
+                push_range(&mut bytes, *range);
+            }
+            None => bytes.push(0),
+        }
+    }
+    bytes
+}
+
+// `None` unless the file starts with `header` and holds a whole program.
+fn decode(bytes: &[u8], header: &[u8], dialect: Dialect) -> Option<JITCache> {
+    let mut reader = Reader(bytes.strip_prefix(header)?);
+    let code = reader.bytes()?;
+    let mut source_map = SourceMap::default();
+    for _ in 0..reader.u32()? {
+        source_map.sites.push(reader.range()?);
+    }
+    for _ in 0..reader.u32()? {
+        let offset = reader.u32()? as usize;
+        let range = match reader.take(1)?[0] {
+            0 => None,
+            _ => Some(reader.range()?),
+        };
+        source_map.blocks.push((offset, range));
+    }
+    if !reader.0.is_empty() || code.is_empty() {
+        return None;
+    }
+    Some(load(code, source_map, dialect))
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    push_u32(bytes, value.len() as u32);
+    bytes.extend_from_slice(value);
+}
+
+fn push_range(bytes: &mut Vec<u8>, range: Range) {
+    for value in [
+        range.start.line,
+        range.start.character,
+        range.end.line,
+        range.end.character,
+    ] {
+        push_u32(bytes, value);
+    }
+}
+
+// Reads what the push functions wrote, `None` past the end.
+struct Reader<'b>(&'b [u8]);
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, length: usize) -> Option<&'b [u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Option<&'b [u8]> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    fn range(&mut self) -> Option<Range> {
+        let start = Position::new(self.u32()?, self.u32()?);
+        let end = Position::new(self.u32()?, self.u32()?);
+        Some(Range::new(start, end))
+    }
+}
+
+// 64 bit FNV-1a, stable across builds unlike the hashers of std.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[test]
+fn test_code_cache() {
+    use brainfuck_analyzer::parse;
+
+    let directory = std::env::temp_dir().join(format!("bfjit-test-{}", process::id()));
+    let cache = CodeCache::new(&directory);
+    let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.";
+    let token_group = parse(source).unwrap().parse_token_group;
+    let dialect = Dialect::default();
+
+    let compiled = cache.compile(source, &token_group, dialect);
+    let entries: Vec<_> = fs::read_dir(&directory).unwrap().collect();
+    assert_eq!(1, entries.len());
+    let path = entries[0].as_ref().unwrap().path();
+
+    // the same program comes back from the file, another dialect is compiled anew
+    let loaded = cache.compile(source, &TokenGroup::default(), dialect);
+    assert_eq!(compiled.code(), loaded.code());
+    assert_eq!(compiled.code_blocks(), loaded.code_blocks());
+    let wide = Dialect {
+        cell_width: crate::CellWidth::Bits16,
+        ..Default::default()
+    };
+    cache.compile(source, &token_group, wide);
+    assert_eq!(2, fs::read_dir(&directory).unwrap().count());
+
+    // a damaged entry is compiled and stored again
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+    let recompiled = cache.compile(source, &token_group, dialect);
+    assert_eq!(compiled.code(), recompiled.code());
+    assert_eq!(bytes, fs::read(&path).unwrap());
+
+    fs::remove_dir_all(&directory).unwrap();
+}
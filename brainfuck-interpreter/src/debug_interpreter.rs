@@ -1,6 +1,8 @@
 use brainfuck_analyzer::{flat_parse, parse, Token, TokenGroup, TokenType};
 
-use crate::jit::IBrainfuckRuntime;
+use crate::dialect::Dialect;
+use crate::error::{RuntimeError, RuntimeErrorKind};
+use crate::tape::Tape;
 use core::time;
 use simplelog::*;
 use std::marker::PhantomData;
@@ -12,51 +14,22 @@ use std::{fs, vec};
 
 pub use brainfuck_analyzer::Position;
 
-pub struct BrainfuckRuntime {
-    pub index: usize,
-    pub memory: Vec<u8>,
-}
-
 #[derive(Clone)]
 pub struct BrainfuckBreakpoint {
     pub position: Position,
     pub id: usize,
 }
 
-impl IBrainfuckRuntime for BrainfuckRuntime {
-    fn get_memory_vec_ptr(&self) -> *const u8 {
-        &self.memory[0] as *const u8
-    }
-
-    fn get_index(&self) -> usize {
-        self.index
-    }
-
-    fn set_index(&mut self, new_index: usize) {
-        self.index = new_index;
-    }
-}
-
-impl BrainfuckRuntime {
-    pub fn new() -> BrainfuckRuntime {
-        BrainfuckRuntime {
-            index: 0,
-            memory: vec![0; 1000],
-        }
-    }
-
-    fn get_memory_data(&self, index: usize) -> u8 {
-        self.memory[index]
-    }
-
-    fn get_memory_size(&self) -> usize {
-        self.memory.len()
-    }
+// byte offset and length of the current cell, used by memory events
+fn current_cell_bytes(tape: &Tape) -> (usize, usize) {
+    let width = tape.dialect.cell_width.bytes();
+    (tape.index * width, width)
 }
 
 pub struct BrainfuckDebugInterpreter<'a> {
     source_file: String,
     source_content: String,
+    dialect: Dialect,
     breakpoints: Vec<BrainfuckBreakpoint>,
     interpreter_debug_command_tx: Option<Sender<InterpreterCommand>>,
     interpreter_debug_user_input_tx: Option<Sender<char>>,
@@ -82,6 +55,7 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
         BrainfuckDebugInterpreter {
             source_file: "unknown".to_string(),
             source_content,
+            dialect: Dialect::default(),
             breakpoints: Vec::new(),
             interpreter_debug_command_tx: None,
             interpreter_debug_user_input_tx: None,
@@ -98,6 +72,7 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
         Ok(BrainfuckDebugInterpreter {
             source_file: source_file.to_string(),
             source_content,
+            dialect: Dialect::default(),
             breakpoints: Vec::new(),
             interpreter_debug_command_tx: None,
             interpreter_debug_user_input_tx: None,
@@ -112,6 +87,11 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
         self.source_file.clone()
     }
 
+    // takes effect on the next launch
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+    }
+
     // pub fn set_breakpoint_callback(&mut self, fn_handler: Box<dyn FnMut(StoppedReasonEnum) + 'a>) {
     //     self.breakpoint_callback = Some(fn_handler);
     // }
@@ -174,11 +154,7 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
         None
     }
 
-    fn handle_command(
-        locals: &mut BrainfuckDebugThreadData,
-        brainfuck_runtime: &mut BrainfuckRuntime,
-        token: &Token,
-    ) {
+    fn handle_command(locals: &mut BrainfuckDebugThreadData, tape: &mut Tape, token: &Token) {
         match locals.interpreter_debug_command_rx.try_recv() {
             Ok(command) => match command {
                 InterpreterCommand::Continue => locals.state = InterpreterState::Running,
@@ -199,10 +175,11 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
                     locals.breakpoints = breakpoints
                 }
                 InterpreterCommand::ReadMemory((start, length)) => {
+                    // raw bytes, a wide cell spans several of them
                     let mut result = vec![];
                     for i in start..start + length {
-                        if i < brainfuck_runtime.get_memory_size() {
-                            result.push(brainfuck_runtime.get_memory_data(i));
+                        if i < tape.memory.len() {
+                            result.push(tape.memory[i]);
                         }
                     }
                     locals
@@ -212,8 +189,10 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
                 }
                 InterpreterCommand::GetVariables => {
                     let mut map = vec![];
-                    map.push(("pos".to_string(), brainfuck_runtime.get_index().to_string()));
-                    let curpos = brainfuck_runtime.get_index() as i64;
+                    // pos counts from the starting cell and is negative left of it, the cells are
+                    // looked up by their index in memory
+                    map.push(("pos".to_string(), tape.position().to_string()));
+                    let curpos = tape.index as i64;
                     for offset in -5..6 {
                         let s = match offset {
                             i64::MIN..=-1 => {
@@ -226,14 +205,10 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
                                 format!("*(pos+{})", offset)
                             }
                         };
-                        if curpos + offset >= 0
-                            && curpos + offset < brainfuck_runtime.get_memory_size() as i64
-                        {
+                        if curpos + offset >= 0 && curpos + offset < tape.cell_count() as i64 {
                             map.push((
                                 s.to_string(),
-                                brainfuck_runtime
-                                    .get_memory_data((curpos + offset) as usize)
-                                    .to_string(),
+                                tape.cell((curpos + offset) as usize).to_string(),
                             ));
                         } else {
                             map.push((s.to_string(), "Out of range".to_string()));
@@ -252,7 +227,7 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
 
     pub fn interpret_token(
         locals: &mut BrainfuckDebugThreadData,
-        brainfuck_runtime: &mut BrainfuckRuntime,
+        tape: &mut Tape,
         token: &Token,
     ) -> bool {
         loop {
@@ -276,7 +251,7 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
                 locals.state = InterpreterState::Paused(token.clone());
             }
 
-            BrainfuckDebugInterpreter::handle_command(locals, brainfuck_runtime, token);
+            BrainfuckDebugInterpreter::handle_command(locals, tape, token);
 
             match &locals.state {
                 InterpreterState::Paused(_) => continue,
@@ -288,54 +263,58 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
 
         match &token.token_type {
             TokenType::PointerIncrement => {
-                if brainfuck_runtime.memory.len() - brainfuck_runtime.index == 1 {
-                    brainfuck_runtime
-                        .memory
-                        .resize(brainfuck_runtime.memory.len() * 2, 0);
+                match tape.offset_index(1) {
+                    Ok(index) => tape.index = index,
+                    Err(kind) => return Self::stop_with_error(locals, token, kind),
                 }
-                brainfuck_runtime.index += 1;
 
                 // scope.yield_(StoppedReasonEnum::Step);
             }
             TokenType::PointerDecrement => {
-                if brainfuck_runtime.index == 0 {
-                    panic!("Cannot decrease pointer when pointer index = 0.");
+                let origin = tape.origin;
+                match tape.offset_index(-1) {
+                    Ok(index) => tape.index = index,
+                    Err(kind) => return Self::stop_with_error(locals, token, kind),
+                }
+                // growing on the left moved every cell
+                if tape.origin != origin {
+                    if let Some(bc) = &mut locals.output_callback {
+                        (*bc)(
+                            OutputCategoryEnum::MemoryEvent((0, tape.memory.len())),
+                            "".to_string(),
+                        );
+                    };
                 }
-                brainfuck_runtime.index -= 1;
                 // scope.yield_(StoppedReasonEnum::Step);
             }
             TokenType::Increment => {
-                if brainfuck_runtime.memory[brainfuck_runtime.index] == u8::MAX {
-                    brainfuck_runtime.memory[brainfuck_runtime.index] = u8::MIN;
-                } else {
-                    brainfuck_runtime.memory[brainfuck_runtime.index] += 1;
+                if let Err(kind) = tape.add_cell(tape.index, 1) {
+                    return Self::stop_with_error(locals, token, kind);
                 }
                 // scope.yield_(StoppedReasonEnum::Step);
 
                 if let Some(bc) = &mut locals.output_callback {
                     (*bc)(
-                        OutputCategoryEnum::MemoryEvent((brainfuck_runtime.index, 1)),
+                        OutputCategoryEnum::MemoryEvent(current_cell_bytes(tape)),
                         "".to_string(),
                     );
                 };
             }
             TokenType::Decrement => {
-                if brainfuck_runtime.memory[brainfuck_runtime.index] == u8::MIN {
-                    brainfuck_runtime.memory[brainfuck_runtime.index] = u8::MAX;
-                } else {
-                    brainfuck_runtime.memory[brainfuck_runtime.index] -= 1;
+                if let Err(kind) = tape.add_cell(tape.index, -1) {
+                    return Self::stop_with_error(locals, token, kind);
                 }
                 // scope.yield_(StoppedReasonEnum::Step);
 
                 if let Some(bc) = &mut locals.output_callback {
                     (*bc)(
-                        OutputCategoryEnum::MemoryEvent((brainfuck_runtime.index, 1)),
+                        OutputCategoryEnum::MemoryEvent(current_cell_bytes(tape)),
                         "".to_string(),
                     );
                 };
             }
             TokenType::Output => {
-                let c: char = brainfuck_runtime.memory[brainfuck_runtime.index].into();
+                let c: char = (tape.current_cell() as u8).into();
                 if let Some(oc) = &mut locals.output_callback {
                     (*oc)(OutputCategoryEnum::StdOut, c.to_string());
                 }
@@ -348,11 +327,11 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
                 loop {
                     if let Ok(input_char) = locals.interpreter_debug_user_input_rx.try_recv() {
                         debug!("Get `{}` from user input", input_char);
-                        brainfuck_runtime.memory[brainfuck_runtime.index] = input_char as u8;
+                        tape.set_cell(tape.index, input_char as u8 as u32);
 
                         if let Some(bc) = &mut locals.output_callback {
                             (*bc)(
-                                OutputCategoryEnum::MemoryEvent((brainfuck_runtime.index, 1)),
+                                OutputCategoryEnum::MemoryEvent(current_cell_bytes(tape)),
                                 "".to_string(),
                             );
                         };
@@ -374,9 +353,9 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
                 }
             }
             TokenType::SubGroup(sg) => {
-                while brainfuck_runtime.memory[brainfuck_runtime.index] != 0 {
+                while tape.current_cell() != 0 {
                     for token in sg.tokens().into_iter() {
-                        if Self::interpret_token(locals, brainfuck_runtime, token) == false {
+                        if Self::interpret_token(locals, tape, token) == false {
                             return false;
                         }
                     }
@@ -399,6 +378,30 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
         true
     }
 
+    // Tell the client why the program stopped. Returns false, so the caller terminates like on a Terminate command.
+    fn stop_with_error(
+        locals: &mut BrainfuckDebugThreadData,
+        token: &Token,
+        kind: RuntimeErrorKind,
+    ) -> bool {
+        let error = RuntimeError {
+            kind,
+            range: token.range,
+        };
+        if let Some(oc) = &mut locals.output_callback {
+            (*oc)(
+                OutputCategoryEnum::Console,
+                format!(
+                    "{} ({}:{})",
+                    error,
+                    error.range.start.line + 1,
+                    error.range.start.character + 1
+                ),
+            );
+        }
+        false
+    }
+
     // fn _insert_breakpoints(vec_token: &mut Vec<Token>, breakpoint_lines: &mut Vec<Position>) {
     //     let mut indexs = vec![];
     //     for i in 0..vec_token.len() {
@@ -462,6 +465,7 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
             unsafe { transmute(output_callback) };
         let token_group = parse_result.parse_token_group;
         let breakpoints = self.breakpoints.clone();
+        let dialect = self.dialect;
         let (interpreter_debug_start_tx, interpreter_debug_start_rx) = mpsc::channel();
         let (interpreter_debug_user_tx, interpreter_debug_user_rx) = mpsc::channel();
         let (interpreter_debug_response_tx, interpreter_debug_response_rx) = mpsc::channel();
@@ -478,17 +482,21 @@ impl<'a> BrainfuckDebugInterpreter<'a> {
                 breakpoint_callback: bc,
                 output_callback: oc,
             };
-            Self::debug_thread(debug_data, token_group);
+            Self::debug_thread(debug_data, token_group, dialect);
         }));
         info!("<< debug_interpreter launch function");
     }
 
-    fn debug_thread(mut debug_data: BrainfuckDebugThreadData, token_group: TokenGroup) {
+    fn debug_thread(
+        mut debug_data: BrainfuckDebugThreadData,
+        token_group: TokenGroup,
+        dialect: Dialect,
+    ) {
         info!(">> debug_interpreter debug_thread function");
-        let mut memory = BrainfuckRuntime::new();
+        let mut tape = Tape::new(dialect);
 
         for token in token_group.tokens().into_iter() {
-            if Self::interpret_token(&mut debug_data, &mut memory, token) == false {
+            if Self::interpret_token(&mut debug_data, &mut tape, token) == false {
                 if let Some(bc) = &mut debug_data.breakpoint_callback {
                     (*bc)(StoppedReasonEnum::Terminated, Some(token.range.start), None);
                     return;
@@ -0,0 +1,204 @@
+use std::str::FromStr;
+
+use crate::error::RuntimeErrorKind;
+use brainfuck_analyzer::{lower_with, recognize_idioms_with, Instruction, TokenGroup};
+
+/// Width of one memory cell. Memory is kept as bytes, each cell takes `bytes()` of them in little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellWidth {
+    #[default]
+    Bits8,
+    Bits16,
+    Bits32,
+}
+
+/// What `+` and `-` do when the cell goes past its maximum or below zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowBehaviour {
+    #[default]
+    Wrap,
+    Error,
+}
+
+/// What `,` stores into the current cell when the input is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EofBehaviour {
+    #[default]
+    Unchanged,
+    Zero,
+    MinusOne,
+    Error,
+}
+
+/// Whether the pointer may move left of the starting cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TapeGrowth {
+    /// Moving left of the starting cell is an error.
+    #[default]
+    Right,
+    /// The tape grows in both directions.
+    Both,
+}
+
+/// The brainfuck convention a program is written for, accepted by every engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Dialect {
+    pub cell_width: CellWidth,
+    pub overflow: OverflowBehaviour,
+    pub eof: EofBehaviour,
+    pub tape: TapeGrowth,
+}
+
+impl CellWidth {
+    pub fn bytes(self) -> usize {
+        match self {
+            CellWidth::Bits8 => 1,
+            CellWidth::Bits16 => 2,
+            CellWidth::Bits32 => 4,
+        }
+    }
+
+    pub fn max_value(self) -> u32 {
+        match self {
+            CellWidth::Bits8 => u8::MAX as u32,
+            CellWidth::Bits16 => u16::MAX as u32,
+            CellWidth::Bits32 => u32::MAX,
+        }
+    }
+
+    /// Read the cell at `index`, counted in cells rather than bytes.
+    pub fn read(self, memory: &[u8], index: usize) -> u32 {
+        let offset = index * self.bytes();
+        match self {
+            CellWidth::Bits8 => memory[offset] as u32,
+            CellWidth::Bits16 => u16::from_le_bytes([memory[offset], memory[offset + 1]]) as u32,
+            CellWidth::Bits32 => u32::from_le_bytes(memory[offset..offset + 4].try_into().unwrap()),
+        }
+    }
+
+    /// Write the cell at `index`, `value` must fit into the cell.
+    pub fn write(self, memory: &mut [u8], index: usize, value: u32) {
+        let offset = index * self.bytes();
+        let bytes = value.to_le_bytes();
+        memory[offset..offset + self.bytes()].copy_from_slice(&bytes[..self.bytes()]);
+    }
+}
+
+impl Dialect {
+    /// Add `value` to a cell, `None` when the result does not fit and overflow is an error.
+    pub fn add(&self, cell: u32, value: i64) -> Option<u32> {
+        let modulus = self.cell_width.max_value() as i64 + 1;
+        let result = cell as i64 + value;
+        match self.overflow {
+            OverflowBehaviour::Wrap => Some(result.rem_euclid(modulus) as u32),
+            OverflowBehaviour::Error if (0..modulus).contains(&result) => Some(result as u32),
+            OverflowBehaviour::Error => None,
+        }
+    }
+
+    /// Value `,` stores when the input is exhausted, `None` leaves the cell unchanged.
+    pub fn eof_value(&self) -> Result<Option<u32>, RuntimeErrorKind> {
+        match self.eof {
+            EofBehaviour::Unchanged => Ok(None),
+            EofBehaviour::Zero => Ok(Some(0)),
+            EofBehaviour::MinusOne => Ok(Some(self.cell_width.max_value())),
+            EofBehaviour::Error => Err(RuntimeErrorKind::EndOfInput),
+        }
+    }
+
+    // The program lowered with idioms recognized, folding only runs that fail the same way
+    // as their commands one by one.
+    pub(crate) fn instructions(&self, token_group: &TokenGroup) -> Vec<Instruction> {
+        let cells_wrap = self.overflow == OverflowBehaviour::Wrap;
+        recognize_idioms_with(&lower_with(token_group, cells_wrap), cells_wrap)
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellWidth::Bits8),
+            "16" => Ok(CellWidth::Bits16),
+            "32" => Ok(CellWidth::Bits32),
+            _ => Err(format!("Invalid cell width `{}`, expected 8, 16 or 32.", s)),
+        }
+    }
+}
+
+impl FromStr for OverflowBehaviour {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(OverflowBehaviour::Wrap),
+            "error" => Ok(OverflowBehaviour::Error),
+            _ => Err(format!(
+                "Invalid overflow behaviour `{}`, expected wrap or error.",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for EofBehaviour {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unchanged" => Ok(EofBehaviour::Unchanged),
+            "zero" => Ok(EofBehaviour::Zero),
+            "minus-one" => Ok(EofBehaviour::MinusOne),
+            "error" => Ok(EofBehaviour::Error),
+            _ => Err(format!(
+                "Invalid EOF behaviour `{}`, expected unchanged, zero, minus-one or error.",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for TapeGrowth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "right" => Ok(TapeGrowth::Right),
+            "both" => Ok(TapeGrowth::Both),
+            _ => Err(format!(
+                "Invalid tape growth `{}`, expected right or both.",
+                s
+            )),
+        }
+    }
+}
+
+#[test]
+fn test_dialect_add() {
+    let wrap16 = Dialect {
+        cell_width: CellWidth::Bits16,
+        ..Default::default()
+    };
+    assert_eq!(Some(0), wrap16.add(u16::MAX as u32, 1));
+    assert_eq!(Some(u16::MAX as u32), wrap16.add(0, -1));
+    assert_eq!(Some(300), wrap16.add(0, 300));
+
+    let error8 = Dialect {
+        overflow: OverflowBehaviour::Error,
+        ..Default::default()
+    };
+    assert_eq!(Some(255), error8.add(254, 1));
+    assert_eq!(None, error8.add(255, 1));
+    assert_eq!(None, error8.add(0, -1));
+}
+
+#[test]
+fn test_cell_width_read_write() {
+    let mut memory = vec![0; 8];
+    CellWidth::Bits32.write(&mut memory, 1, 0x01020304);
+    assert_eq!(vec![0, 0, 0, 0, 4, 3, 2, 1], memory);
+    assert_eq!(0x01020304, CellWidth::Bits32.read(&memory, 1));
+    assert_eq!(0x0304, CellWidth::Bits16.read(&memory, 2));
+    assert_eq!(Err(()), "64".parse::<CellWidth>().map_err(|_| ()));
+}
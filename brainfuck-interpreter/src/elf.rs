@@ -0,0 +1,369 @@
+use crate::dialect::{CellWidth, Dialect, TapeGrowth};
+use crate::error::RuntimeErrorKind;
+use crate::jit::{_compile, code_size_bound, SourceMap, Target, MAX_SEQUENCE_BYTES};
+use assembler::mnemonic_parameter_types::memory::MemoryOperand;
+use assembler::mnemonic_parameter_types::registers::Register32Bit::{EAX, EDI, EDX};
+use assembler::mnemonic_parameter_types::registers::Register64Bit::*;
+use assembler::mnemonic_parameter_types::registers::Register8Bit::AL;
+use assembler::mnemonic_parameter_types::Label;
+use assembler::ExecutableAnonymousMemoryMap::ExecutableAnonymousMemoryMap;
+use assembler::InstructionStream::InstructionStream;
+use assembler::InstructionStreamHints::InstructionStreamHints;
+use brainfuck_analyzer::{parse, ParseError, Range};
+
+// The usual load address of static x86-64 executables. The headers and the code share one segment.
+const LOAD_ADDRESS: u64 = 0x400000;
+// ELF header and the program headers for the code, the tape and the stack.
+const HEADERS_SIZE: u64 = 64 + 3 * 56;
+// The tape gets a zero filled segment of its own, far enough from the code.
+const TAPE_ADDRESS: u64 = 0x10000000;
+// End of the user half of the x86-64 address space, the tape has to fit below it.
+const ADDRESS_SPACE_END: u64 = 1 << 47;
+
+const SYS_READ: u32 = 0;
+const SYS_WRITE: u32 = 1;
+const SYS_EXIT: u32 = 60;
+
+// Errors an executable can stop with, each gets a message and a routine printing it.
+const FAILURES: [RuntimeErrorKind; 4] = [
+    RuntimeErrorKind::PointerUnderflow,
+    RuntimeErrorKind::CellOverflow,
+    RuntimeErrorKind::EndOfInput,
+    RuntimeErrorKind::MemoryLimitExceeded,
+];
+
+// What the code generator needs to know about a standalone executable.
+#[derive(Clone, Copy)]
+pub(crate) struct ElfRuntime {
+    // size of the tape, offsets at or past it are out of bounds
+    tape_bytes: u64,
+    // the routine stopping with the error of the same index in FAILURES
+    failure_labels: [Label; FAILURES.len()],
+}
+
+/// Compiles the program into a static x86-64 Linux executable. It talks to the kernel with raw
+/// syscalls, so it runs without this crate or even a libc installed.
+///
+/// The tape has room for `max_cells` cells on each side the dialect lets it grow to, moving past
+/// them stops the program like the memory limit of the engines. Runtime errors print their message
+/// to stderr and exit with status 1. A tape too large for the address space is reported as an error
+/// at the start of the source.
+pub fn compile_elf(
+    source: &str,
+    dialect: Dialect,
+    max_cells: usize,
+) -> Result<Vec<u8>, ParseError> {
+    let parse_result = parse(source)?;
+    let instructions = dialect.instructions(&parse_result.parse_token_group);
+
+    let (tape_bytes, origin) = tape_layout(dialect, max_cells).ok_or_else(|| ParseError {
+        range: Range::default(),
+        error_message: format!(
+            "A tape of {} cells on each side does not fit into the executable.",
+            max_cells
+        ),
+    })?;
+
+    // the program, plus the entry, the final exit and the failure routines with their messages
+    let map_size = code_size_bound(&instructions) + 16 * MAX_SEQUENCE_BYTES;
+    let mut memory_map = ExecutableAnonymousMemoryMap::new(map_size, false, true)
+        .expect("Could not anonymously mmap");
+    let mut instruction_stream = memory_map.instruction_stream(&InstructionStreamHints::default());
+
+    // the messages come first, so their addresses are known before the code printing them
+    let mut address = LOAD_ADDRESS + HEADERS_SIZE;
+    let mut messages = vec![];
+    for kind in FAILURES {
+        let message = format!("error: {}\n", kind);
+        instruction_stream.emit_bytes(message.as_bytes());
+        messages.push((address, message.len() as u32));
+        address += message.len() as u64;
+    }
+    let entry = address;
+
+    let runtime = ElfRuntime {
+        tape_bytes,
+        failure_labels: FAILURES.map(|_| instruction_stream.create_label()),
+    };
+
+    // RDI = head of the tape, RSI = offset of the starting cell, as in the JIT
+    instruction_stream.mov_Register64Bit_Immediate64Bit(RDI, TAPE_ADDRESS.into());
+    instruction_stream.mov_Register64Bit_Immediate64Bit(RSI, origin.into());
+    _compile(
+        &instructions,
+        &mut instruction_stream,
+        dialect,
+        Target::Elf(runtime),
+        &mut SourceMap::default(),
+    );
+    emit_exit_syscall(&mut instruction_stream, 0);
+
+    for (label, (address, length)) in runtime.failure_labels.into_iter().zip(messages) {
+        instruction_stream.attach_label(label);
+        instruction_stream.mov_Register32Bit_Immediate32Bit(EDI, 2u32.into());
+        instruction_stream.mov_Register64Bit_Immediate64Bit(RSI, address.into());
+        instruction_stream.mov_Register32Bit_Immediate32Bit(EDX, length.into());
+        instruction_stream.mov_Register32Bit_Immediate32Bit(EAX, SYS_WRITE.into());
+        instruction_stream.syscall();
+        emit_exit_syscall(&mut instruction_stream, 1);
+    }
+
+    let (code, _) = instruction_stream.finish();
+    Ok(elf_file(code, entry, tape_bytes))
+}
+
+impl ElfRuntime {
+    pub(crate) fn failure_label(&self, kind: RuntimeErrorKind) -> Label {
+        let index = FAILURES
+            .iter()
+            .position(|failure| *failure == kind)
+            .unwrap();
+        self.failure_labels[index]
+    }
+
+    // Write the low byte of the current cell to stdout. A failed write is ignored, like a closed
+    // stdout would be by most programs.
+    pub(crate) fn emit_output(&self, instruction_stream: &mut InstructionStream) {
+        emit_cell_syscall(instruction_stream, SYS_WRITE, 1);
+    }
+
+    // Read one byte from stdin into the current cell, the dialect decides what happens at end of input.
+    pub(crate) fn emit_input(&self, instruction_stream: &mut InstructionStream, dialect: Dialect) {
+        let cell = MemoryOperand::base_64_index_64(RDI, RSI);
+        let read_label = instruction_stream.create_label();
+        let done_label = instruction_stream.create_label();
+
+        emit_cell_syscall(instruction_stream, SYS_READ, 0);
+        instruction_stream.cmp_Register64Bit_Immediate32Bit(RAX, 1i32.into());
+        instruction_stream.jz_Label(read_label);
+        // anything but the end of input is a failed read
+        instruction_stream.cmp_Register64Bit_Immediate32Bit(RAX, 0i32.into());
+        instruction_stream.jnz_Label_1(self.failure_label(RuntimeErrorKind::EndOfInput));
+        match dialect.eof_value() {
+            Ok(None) => (),
+            Ok(Some(value)) => match dialect.cell_width {
+                CellWidth::Bits8 => instruction_stream
+                    .mov_Any8BitMemory_Immediate8Bit(cell.into(), (value as u8).into()),
+                CellWidth::Bits16 => instruction_stream
+                    .mov_Any16BitMemory_Immediate16Bit(cell.into(), (value as u16).into()),
+                CellWidth::Bits32 => {
+                    instruction_stream.mov_Any32BitMemory_Immediate32Bit(cell.into(), value.into())
+                }
+            },
+            Err(kind) => instruction_stream.jmp_Label_1(self.failure_label(kind)),
+        }
+        instruction_stream.jmp_Label(done_label);
+
+        // the byte went into the lowest byte of the cell, clear the others
+        instruction_stream.attach_label(read_label);
+        match dialect.cell_width {
+            CellWidth::Bits8 => (),
+            CellWidth::Bits16 => {
+                instruction_stream.mov_Register8Bit_Any8BitMemory(AL, cell.into());
+                instruction_stream.mov_Any16BitMemory_Immediate16Bit(cell.into(), 0u16.into());
+                instruction_stream.mov_Any8BitMemory_Register8Bit(cell.into(), AL);
+            }
+            CellWidth::Bits32 => {
+                instruction_stream.mov_Register8Bit_Any8BitMemory(AL, cell.into());
+                instruction_stream.mov_Any32BitMemory_Immediate32Bit(cell.into(), 0u32.into());
+                instruction_stream.mov_Any8BitMemory_Register8Bit(cell.into(), AL);
+            }
+        }
+        instruction_stream.attach_label(done_label);
+    }
+
+    // Stop with a memory limit error unless the byte at RSI + offset is on the tape. Left of the
+    // tape the offset wraps around and is out of bounds as well.
+    pub(crate) fn emit_tape_check(
+        &self,
+        instruction_stream: &mut InstructionStream,
+        offset: isize,
+    ) {
+        instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RAX, RSI);
+        instruction_stream.add_Register64Bit_Immediate32Bit(RAX, (offset as i32).into());
+        instruction_stream.mov_Register64Bit_Immediate64Bit(RCX, self.tape_bytes.into());
+        instruction_stream.cmp_Register64Bit_Register64Bit_r64_rm64(RAX, RCX);
+        instruction_stream.jae_Label_1(self.failure_label(RuntimeErrorKind::MemoryLimitExceeded));
+    }
+}
+
+// `syscall(fd, address of the current cell, 1)`, keeping the tape head and offset, which are
+// arguments of the syscall. The result is in RAX.
+fn emit_cell_syscall(instruction_stream: &mut InstructionStream, number: u32, fd: u32) {
+    instruction_stream.push_Register64Bit_r64(RDI);
+    instruction_stream.push_Register64Bit_r64(RSI);
+
+    instruction_stream.add_Register64Bit_Register64Bit_r64_rm64(RSI, RDI);
+    instruction_stream.mov_Register32Bit_Immediate32Bit(EDI, fd.into());
+    instruction_stream.mov_Register32Bit_Immediate32Bit(EDX, 1u32.into());
+    instruction_stream.mov_Register32Bit_Immediate32Bit(EAX, number.into());
+    instruction_stream.syscall();
+
+    instruction_stream.pop_Register64Bit_r64(RSI);
+    instruction_stream.pop_Register64Bit_r64(RDI);
+}
+
+fn emit_exit_syscall(instruction_stream: &mut InstructionStream, status: u32) {
+    instruction_stream.mov_Register32Bit_Immediate32Bit(EDI, status.into());
+    instruction_stream.mov_Register32Bit_Immediate32Bit(EAX, SYS_EXIT.into());
+    instruction_stream.syscall();
+}
+
+// Size of the tape and offset of the starting cell in bytes, `None` when the tape does not fit
+// into the address space.
+fn tape_layout(dialect: Dialect, max_cells: usize) -> Option<(u64, u64)> {
+    let width = dialect.cell_width.bytes() as u64;
+    // the starting cell is always there
+    let cells = u64::try_from(max_cells.max(1)).ok()?;
+    let (tape_bytes, origin) = match dialect.tape {
+        TapeGrowth::Right => (cells.checked_mul(width)?, 0),
+        TapeGrowth::Both => (
+            cells.checked_mul(2)?.checked_sub(1)?.checked_mul(width)?,
+            (cells - 1) * width,
+        ),
+    };
+    (tape_bytes <= ADDRESS_SPACE_END - TAPE_ADDRESS).then_some((tape_bytes, origin))
+}
+
+// ELF64 file with a readable and executable segment for the headers and the code, a zero filled
+// writable segment for the tape and a non-executable stack.
+// ref data: https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+fn elf_file(code: &[u8], entry: u64, tape_bytes: u64) -> Vec<u8> {
+    let mut file = Vec::new();
+    file.extend_from_slice(b"\x7fELF");
+    // 64 bit, little endian, version 1, System V ABI, padding
+    file.extend_from_slice(&[2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    file.extend_from_slice(&2u16.to_le_bytes()); // executable
+    file.extend_from_slice(&0x3eu16.to_le_bytes()); // x86-64
+    file.extend_from_slice(&1u32.to_le_bytes()); // version
+    file.extend_from_slice(&entry.to_le_bytes());
+    file.extend_from_slice(&64u64.to_le_bytes()); // program headers follow this header
+    file.extend_from_slice(&0u64.to_le_bytes()); // no section headers
+    file.extend_from_slice(&0u32.to_le_bytes()); // flags
+    file.extend_from_slice(&64u16.to_le_bytes()); // size of this header
+    file.extend_from_slice(&56u16.to_le_bytes()); // size of a program header
+    file.extend_from_slice(&3u16.to_le_bytes()); // number of program headers
+    file.extend_from_slice(&[0; 6]); // section header size, count and name index
+
+    let code_size = HEADERS_SIZE + code.len() as u64;
+    push_program_header(
+        &mut file,
+        PT_LOAD,
+        PF_R | PF_X,
+        LOAD_ADDRESS,
+        code_size,
+        code_size,
+    );
+    push_program_header(&mut file, PT_LOAD, PF_R | PF_W, TAPE_ADDRESS, 0, tape_bytes);
+    push_program_header(&mut file, PT_GNU_STACK, PF_R | PF_W, 0, 0, 0);
+    file.extend_from_slice(code);
+    file
+}
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+// A segment starting at the beginning of the file, the rest of its memory is zero filled.
+fn push_program_header(
+    file: &mut Vec<u8>,
+    segment_type: u32,
+    flags: u32,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+) {
+    file.extend_from_slice(&segment_type.to_le_bytes());
+    file.extend_from_slice(&flags.to_le_bytes());
+    file.extend_from_slice(&0u64.to_le_bytes()); // offset in the file
+    file.extend_from_slice(&address.to_le_bytes()); // virtual address
+    file.extend_from_slice(&address.to_le_bytes()); // physical address
+    file.extend_from_slice(&file_size.to_le_bytes());
+    file.extend_from_slice(&memory_size.to_le_bytes());
+    file.extend_from_slice(&0x1000u64.to_le_bytes()); // alignment
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+fn run_elf(name: &str, source: &str, dialect: Dialect, input: &str) -> std::process::Output {
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let executable = compile_elf(source, dialect, 1000).unwrap();
+    std::fs::write(&path, executable).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut child = std::process::Command::new(&path)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn test_elf_tape_layout() {
+    let both = Dialect {
+        cell_width: CellWidth::Bits32,
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    assert_eq!(Some((4, 0)), tape_layout(both, 0));
+    assert_eq!(Some((36, 16)), tape_layout(both, 5));
+    assert_eq!(Some((1000, 0)), tape_layout(Dialect::default(), 1000));
+
+    // sizes that would wrap around or not fit into the address space are rejected
+    assert_eq!(None, tape_layout(both, usize::MAX));
+    assert_eq!(None, tape_layout(both, usize::MAX / 4 + 1));
+    assert_eq!(None, tape_layout(Dialect::default(), 1 << 47));
+    let error = compile_elf("+", both, usize::MAX / 2).unwrap_err();
+    assert!(error.error_message.contains("does not fit"));
+}
+
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn test_elf_runs_standalone() {
+    let dialect = Dialect {
+        cell_width: CellWidth::Bits16,
+        eof: crate::EofBehaviour::Zero,
+        ..Default::default()
+    };
+    let output = run_elf(
+        "test_elf_runs_standalone",
+        "+++[->++++++++<]>[->+>++<<]>+.>,[.,]",
+        dialect,
+        "abc",
+    );
+    assert!(output.status.success());
+    assert_eq!(b"\x19abc".to_vec(), output.stdout);
+}
+
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn test_elf_reports_errors() {
+    let output = run_elf("test_elf_underflow", "+>\n<<", Dialect::default(), "");
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!(
+        b"error: Cannot decrease pointer when pointer index = 0.\n".to_vec(),
+        output.stderr
+    );
+
+    let dialect = Dialect {
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    let output = run_elf("test_elf_memory_limit", "+[<+]", dialect, "");
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!(b"error: Memory limit exceeded.\n".to_vec(), output.stderr);
+}
@@ -0,0 +1,450 @@
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use brainfuck_analyzer::ParseError;
+
+use crate::error::{RuntimeError, RuntimeErrorKind};
+use crate::tape::Tape;
+
+/// A backend able to execute brainfuck programs.
+///
+/// `prepare` parses (and compiles, for the JIT engines) a program and resets the tape, `run`
+/// executes it against the given streams. The tape stays available for inspection afterwards,
+/// also when `run` stopped with an error.
+pub trait Engine {
+    fn prepare(&mut self, source: &str) -> Result<(), ParseError>;
+
+    fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), RuntimeError>;
+
+    /// Limits applied to every following `run`.
+    fn set_limits(&mut self, limits: Limits);
+
+    fn tape(&self) -> &Tape;
+
+    fn tape_mut(&mut self) -> &mut Tape;
+}
+
+/// Bounds for running untrusted programs, `None` leaves a resource unlimited.
+///
+/// A step is one executed instruction or one loop condition check. The JIT engines only count
+/// steps and look at the clock at loop back-edges, so straight-line code may run past a limit.
+/// The timeout cannot interrupt `,` waiting for input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    /// Stop with `StepLimitExceeded` after this many steps.
+    pub max_steps: Option<u64>,
+    /// Stop with `MemoryLimitExceeded` when the pointer moves this many cells away from the start.
+    pub max_cells: Option<usize>,
+    /// Stop with `TimeLimitExceeded` once `run` took this long.
+    pub timeout: Option<Duration>,
+}
+
+// Steps between two looks at the clock.
+const STEPS_BETWEEN_CHECKS: u64 = 1 << 16;
+
+// Bytes of output collected before they are written.
+pub(crate) const OUTPUT_BUFFER_BYTES: usize = 4096;
+
+// Everything a running program touches. Compiled code receives a pointer to it, decrements
+// `steps_left`, reads `memory_end` and `memory_start`, calls `runtime_functions` and fills the
+// output buffer in place, so the fields up to `output_buffer` have to stay first and in this order.
+#[repr(C)]
+pub(crate) struct RunContext<'r> {
+    // steps until the limits are checked again
+    pub steps_left: u64,
+    // byte offsets below this are allocated and within the limits, see Tape::memory_end
+    pub memory_end: u64,
+    // byte offsets from this one on are within the limits, see Tape::memory_start
+    pub memory_start: u64,
+    // addresses of the functions compiled code calls, see jit::runtime_functions
+    pub runtime_functions: [usize; 4],
+    // bytes of `output_buffer` in use
+    pub output_length: u64,
+    pub output_buffer: [u8; OUTPUT_BUFFER_BYTES],
+    // steps of the budget not yet moved into `steps_left`
+    steps_in_reserve: u64,
+    // all steps the run may take
+    step_budget: u64,
+    deadline: Option<Instant>,
+    pub tape: &'r mut Tape,
+    pub input: &'r mut dyn Read,
+    pub output: &'r mut dyn Write,
+    // Set by runtime functions that fail while called from compiled code.
+    pub failure: Option<RuntimeErrorKind>,
+}
+
+impl<'r> RunContext<'r> {
+    pub fn new(
+        tape: &'r mut Tape,
+        input: &'r mut dyn Read,
+        output: &'r mut dyn Write,
+        limits: &Limits,
+    ) -> RunContext<'r> {
+        tape.max_cells = limits.max_cells;
+        RunContext {
+            steps_left: 0,
+            memory_end: 0,
+            memory_start: 0,
+            runtime_functions: [0; 4],
+            output_length: 0,
+            output_buffer: [0; OUTPUT_BUFFER_BYTES],
+            steps_in_reserve: limits.max_steps.unwrap_or(u64::MAX),
+            step_budget: limits.max_steps.unwrap_or(u64::MAX),
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            tape,
+            input,
+            output,
+            failure: None,
+        }
+    }
+
+    pub fn step(&mut self, cost: u64) -> Result<(), RuntimeErrorKind> {
+        let (steps_left, exhausted) = self.steps_left.overflowing_sub(cost);
+        self.steps_left = steps_left;
+        if exhausted {
+            self.refill_steps()
+        } else {
+            Ok(())
+        }
+    }
+
+    // Steps taken since the start of the run.
+    pub fn steps_taken(&self) -> u64 {
+        self.step_budget - self.steps_in_reserve - self.steps_left
+    }
+
+    // Called once `steps_left` wrapped below zero. Checks the limits and moves the next batch of
+    // steps out of the reserve, paying off what was overdrawn.
+    pub fn refill_steps(&mut self) -> Result<(), RuntimeErrorKind> {
+        let overdrawn = self.steps_left.wrapping_neg();
+        self.steps_left = 0;
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(RuntimeErrorKind::TimeLimitExceeded);
+            }
+        }
+        if self.steps_in_reserve < overdrawn {
+            self.steps_in_reserve = 0;
+            return Err(RuntimeErrorKind::StepLimitExceeded);
+        }
+        let batch = self
+            .steps_in_reserve
+            .min(STEPS_BETWEEN_CHECKS.max(overdrawn));
+        self.steps_in_reserve -= batch;
+        self.steps_left = batch - overdrawn;
+        Ok(())
+    }
+
+    // Read one byte into the cell at `index`, the dialect decides what happens at end of input.
+    pub fn input_cell(&mut self, index: usize) -> Result<(), RuntimeErrorKind> {
+        // a prompt has to show before waiting for the answer
+        self.flush_output();
+        let mut byte = [0u8];
+        let value = match self.input.read(&mut byte) {
+            Ok(0) => self.tape.dialect.eof_value()?,
+            Ok(_) => Some(byte[0] as u32),
+            Err(_) => return Err(RuntimeErrorKind::EndOfInput),
+        };
+        if let Some(value) = value {
+            self.tape.set_cell(index, value);
+        }
+        Ok(())
+    }
+
+    // Write the low byte of the cell at `index`. Output is buffered until a newline, a full buffer,
+    // the next input or the end of the run.
+    pub fn output_cell(&mut self, index: usize) {
+        let byte = self.tape.cell(index) as u8;
+        self.output_buffer[self.output_length as usize] = byte;
+        self.output_length += 1;
+        if byte == b'\n' || self.output_length as usize == OUTPUT_BUFFER_BYTES {
+            self.flush_output();
+        }
+    }
+
+    // Write out and flush the buffered output, engines call it when a run ends.
+    pub fn flush_output(&mut self) {
+        if self.output_length == 0 {
+            return;
+        }
+        self.output
+            .write_all(&self.output_buffer[..self.output_length as usize])
+            .unwrap();
+        self.output_length = 0;
+        self.output.flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+fn run_all_engines(source: &str, input: &str, dialect: crate::Dialect) -> Vec<(Vec<u8>, Tape)> {
+    let engines: Vec<Box<dyn Engine>> = vec![
+        Box::new(crate::InterpreterEngine::new(dialect)),
+        Box::new(crate::JITEngine::new(dialect)),
+        Box::new(crate::AutoJITEngine::new(dialect)),
+        Box::new(crate::VMEngine::new(dialect)),
+    ];
+    engines
+        .into_iter()
+        .map(|mut engine| {
+            let mut output = Vec::new();
+            engine.prepare(source).unwrap();
+            engine.run(&mut input.as_bytes(), &mut output).unwrap();
+            (output, engine.tape().clone())
+        })
+        .collect()
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_engines_agree() {
+    let sources = [
+        (
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.",
+            "",
+        ),
+        (",[.,]", "echo"),
+        ("+++[->++>+++<<]>>[-<+>]<[<]>>,.", "x"),
+    ];
+    for (source, input) in sources {
+        for cell_width in [crate::CellWidth::Bits8, crate::CellWidth::Bits16] {
+            let dialect = crate::Dialect {
+                cell_width,
+                eof: crate::EofBehaviour::Zero,
+                ..Default::default()
+            };
+            let results = run_all_engines(source, input, dialect);
+            assert_eq!(
+                results[0], results[1],
+                "interpreter and jit differ on {}",
+                source
+            );
+            assert_eq!(
+                results[0], results[2],
+                "interpreter and autojit differ on {}",
+                source
+            );
+            assert_eq!(
+                results[0], results[3],
+                "interpreter and vm differ on {}",
+                source
+            );
+        }
+    }
+
+    // the same, plus programs that only run on a tape growing to the left
+    let sources = [
+        (",[.,]", "echo"),
+        ("+++[-<<++>+++>]<<[->>+<<]>.<[<]+<<<.", ""),
+        ("++++[-<<+>>]<<[-<+++>]<.<<+[>]<<[<]>.", ""),
+    ];
+    for (source, input) in sources {
+        for cell_width in [crate::CellWidth::Bits8, crate::CellWidth::Bits16] {
+            let dialect = crate::Dialect {
+                cell_width,
+                eof: crate::EofBehaviour::Zero,
+                tape: crate::TapeGrowth::Both,
+                ..Default::default()
+            };
+            let results = run_all_engines(source, input, dialect);
+            assert_eq!(
+                results[0], results[1],
+                "interpreter and jit differ on {}",
+                source
+            );
+            assert_eq!(
+                results[0], results[2],
+                "interpreter and autojit differ on {}",
+                source
+            );
+            assert_eq!(
+                results[0], results[3],
+                "interpreter and vm differ on {}",
+                source
+            );
+        }
+    }
+}
+
+#[test]
+fn test_step_limit() {
+    let mut engine = crate::InterpreterEngine::new(crate::Dialect::default());
+    engine.prepare("+[]").unwrap();
+    engine.set_limits(Limits {
+        max_steps: Some(100),
+        ..Default::default()
+    });
+    let error = engine.run(&mut std::io::empty(), &mut std::io::sink());
+    assert_eq!(RuntimeErrorKind::StepLimitExceeded, error.unwrap_err().kind);
+    assert_eq!(1, engine.tape().current_cell());
+
+    // exactly enough steps: `+`, entering the loop, the folded `>>` and the next check
+    engine.prepare("+[>>]").unwrap();
+    engine.set_limits(Limits {
+        max_steps: Some(4),
+        ..Default::default()
+    });
+    assert_eq!(
+        Ok(()),
+        engine.run(&mut std::io::empty(), &mut std::io::sink())
+    );
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_engines_enforce_limits() {
+    use std::time::Duration;
+
+    let cases = [
+        (
+            "+[>+]",
+            Limits {
+                max_cells: Some(100),
+                ..Default::default()
+            },
+            RuntimeErrorKind::MemoryLimitExceeded,
+        ),
+        (
+            "+[]",
+            Limits {
+                timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+            RuntimeErrorKind::TimeLimitExceeded,
+        ),
+        (
+            "+[+-]",
+            Limits {
+                max_steps: Some(100_000),
+                ..Default::default()
+            },
+            RuntimeErrorKind::StepLimitExceeded,
+        ),
+        // the scans walk the whole tape every round, their moves run out of steps long before
+        // the tape runs out of cells
+        (
+            ">+[[<]>[>]+]",
+            Limits {
+                max_cells: Some(100),
+                max_steps: Some(2_000),
+                ..Default::default()
+            },
+            RuntimeErrorKind::StepLimitExceeded,
+        ),
+    ];
+    for (source, limits, kind) in cases {
+        let dialect = crate::Dialect::default();
+        let engines: Vec<Box<dyn Engine>> = vec![
+            Box::new(crate::InterpreterEngine::new(dialect)),
+            Box::new(crate::JITEngine::new(dialect)),
+            Box::new(crate::AutoJITEngine::new(dialect)),
+            Box::new(crate::VMEngine::new(dialect)),
+        ];
+        for mut engine in engines {
+            engine.prepare(source).unwrap();
+            engine.set_limits(limits);
+            let error = engine.run(&mut std::io::empty(), &mut std::io::sink());
+            assert_eq!(kind, error.unwrap_err().kind, "on {}", source);
+        }
+    }
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_engines_report_error_range() {
+    use brainfuck_analyzer::{Position, Range};
+
+    let at = |line, character| {
+        Range::new(
+            Position::new(line, character),
+            Position::new(line, character + 1),
+        )
+    };
+    let at_maximum = format!("{}><+-", "+".repeat(255));
+    let cases = [
+        ("+>.\n<.<", RuntimeErrorKind::PointerUnderflow, at(1, 2)),
+        ("+<>", RuntimeErrorKind::PointerUnderflow, at(0, 1)),
+        ("+[,]", RuntimeErrorKind::EndOfInput, at(0, 2)),
+        ("+>-", RuntimeErrorKind::CellOverflow, at(0, 2)),
+        // runs turning around are not folded away, the first command overflows
+        ("-+", RuntimeErrorKind::CellOverflow, at(0, 0)),
+        (
+            at_maximum.as_str(),
+            RuntimeErrorKind::CellOverflow,
+            at(0, 257),
+        ),
+        // loops going below zero halfway through an iteration are not turned into multiplications
+        ("+[->+<->+<+]", RuntimeErrorKind::CellOverflow, at(0, 6)),
+        ("+[->+<-+]", RuntimeErrorKind::CellOverflow, at(0, 6)),
+    ];
+    let dialect = crate::Dialect {
+        overflow: crate::OverflowBehaviour::Error,
+        eof: crate::EofBehaviour::Error,
+        ..Default::default()
+    };
+    for (source, kind, range) in cases {
+        let engines: Vec<Box<dyn Engine>> = vec![
+            Box::new(crate::InterpreterEngine::new(dialect)),
+            Box::new(crate::JITEngine::new(dialect)),
+            Box::new(crate::AutoJITEngine::new(dialect)),
+            Box::new(crate::VMEngine::new(dialect)),
+        ];
+        for mut engine in engines {
+            engine.prepare(source).unwrap();
+            let error = engine.run(&mut std::io::empty(), &mut std::io::sink());
+            assert_eq!(Err(RuntimeError { kind, range }), error, "on {}", source);
+        }
+    }
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_engines_buffer_output() {
+    use std::cell::RefCell;
+
+    // what the program did to its streams, in order
+    struct Log<'l>(&'l RefCell<Vec<String>>);
+    impl Read for Log<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().push("read".to_string());
+            buf[0] = b'!';
+            Ok(1)
+        }
+    }
+    impl Write for Log<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let text = String::from_utf8_lossy(buf);
+            self.0.borrow_mut().push(format!("write {:?}", text));
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.borrow_mut().push("flush".to_string());
+            Ok(())
+        }
+    }
+
+    // "ab\nab" then a prompt, the answer and "a" again
+    let source = "++++++++++>+++++++++[->+++++++++++>+++++++++++<<]>-->-<.>.<<<.>>.>.,.<.";
+    let engines: Vec<Box<dyn Engine>> = vec![
+        Box::new(crate::InterpreterEngine::new(crate::Dialect::default())),
+        Box::new(crate::JITEngine::new(crate::Dialect::default())),
+        Box::new(crate::AutoJITEngine::new(crate::Dialect::default())),
+        Box::new(crate::VMEngine::new(crate::Dialect::default())),
+    ];
+    for mut engine in engines {
+        let log = RefCell::new(Vec::new());
+        engine.prepare(source).unwrap();
+        engine.run(&mut Log(&log), &mut Log(&log)).unwrap();
+        assert_eq!(
+            vec![
+                "write \"ab\\n\"",
+                "flush",
+                "write \"ab\"",
+                "flush",
+                "read",
+                "write \"!a\"",
+                "flush"
+            ],
+            log.into_inner()
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+use brainfuck_analyzer::{ParseError, Range};
+
+/// Why a program stopped before reaching its end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    /// The pointer moved left of the first cell.
+    PointerUnderflow,
+    /// A cell went past its maximum or below zero and the dialect treats overflow as an error.
+    CellOverflow,
+    /// `,` found no more input and the dialect treats that as an error, or reading the input failed.
+    EndOfInput,
+    /// The tape could not grow any further, or the pointer passed the cell limit.
+    MemoryLimitExceeded,
+    /// The program ran more steps than the limit allows.
+    StepLimitExceeded,
+    /// The program ran longer than the timeout.
+    TimeLimitExceeded,
+}
+
+/// A runtime failure together with the source range of the token that caused it.
+///
+/// Engines running lowered instructions report the range of the whole run folded into the failing instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub range: Range,
+}
+
+/// Everything the `interpret*` helpers can fail with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecutionError {
+    Parse(ParseError),
+    Runtime(RuntimeError),
+}
+
+impl RuntimeError {
+    /// The error at the span covering every range, used for instructions folded from several tokens.
+    pub fn spanning(kind: RuntimeErrorKind, ranges: &[Range]) -> RuntimeError {
+        RuntimeError {
+            kind,
+            range: span(ranges),
+        }
+    }
+}
+
+// The range from the start of the first to the end of the last range.
+pub(crate) fn span(ranges: &[Range]) -> Range {
+    match (ranges.first(), ranges.last()) {
+        (Some(first), Some(last)) => Range::new(first.start, last.end),
+        _ => Range::default(),
+    }
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            RuntimeErrorKind::PointerUnderflow => "Cannot decrease pointer when pointer index = 0.",
+            RuntimeErrorKind::CellOverflow => "Cell overflow.",
+            RuntimeErrorKind::EndOfInput => "Unexpected end of input.",
+            RuntimeErrorKind::MemoryLimitExceeded => "Memory limit exceeded.",
+            RuntimeErrorKind::StepLimitExceeded => "Step limit exceeded.",
+            RuntimeErrorKind::TimeLimitExceeded => "Time limit exceeded.",
+        };
+        f.write_str(message)
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+impl From<ParseError> for ExecutionError {
+    fn from(error: ParseError) -> Self {
+        ExecutionError::Parse(error)
+    }
+}
+
+impl From<RuntimeError> for ExecutionError {
+    fn from(error: RuntimeError) -> Self {
+        ExecutionError::Runtime(error)
+    }
+}
+
+#[test]
+fn test_runtime_error_spanning() {
+    use brainfuck_analyzer::Position;
+
+    let ranges = [
+        Range::new(Position::new(0, 1), Position::new(0, 2)),
+        Range::new(Position::new(1, 0), Position::new(1, 1)),
+    ];
+    let error = RuntimeError::spanning(RuntimeErrorKind::PointerUnderflow, &ranges);
+    assert_eq!(
+        Range::new(Position::new(0, 1), Position::new(1, 1)),
+        error.range
+    );
+}
@@ -1,90 +1,226 @@
-use std::io::Read;
+use std::io::{Read, Write};
 
-use brainfuck_analyzer::{parse, Token, TokenType};
+use brainfuck_analyzer::{parse, Instruction, Op, ParseError};
 
-use crate::jit::IBrainfuckRuntime;
+use crate::dialect::Dialect;
+use crate::engine::{Engine, Limits, RunContext};
+use crate::error::{ExecutionError, RuntimeError};
+use crate::tape::Tape;
 
-pub struct BrainfuckMemory {
-    pub index: usize,
-    pub memory: Vec<u8>,
+/// Executes the lowered instructions one by one.
+pub struct InterpreterEngine {
+    instructions: Vec<Instruction>,
+    tape: Tape,
+    limits: Limits,
 }
 
-impl IBrainfuckRuntime for BrainfuckMemory {
-    fn get_memory_vec_ptr(&self) -> *const u8 {
-        &self.memory[0] as *const u8
+impl InterpreterEngine {
+    pub fn new(dialect: Dialect) -> InterpreterEngine {
+        InterpreterEngine {
+            instructions: Vec::new(),
+            tape: Tape::new(dialect),
+            limits: Limits::default(),
+        }
     }
+}
 
-    fn get_index(&self) -> usize {
-        self.index
+impl Engine for InterpreterEngine {
+    fn prepare(&mut self, source: &str) -> Result<(), ParseError> {
+        let parse_result = parse(source)?;
+        self.instructions = self
+            .tape
+            .dialect
+            .instructions(&parse_result.parse_token_group);
+        self.tape = Tape::new(self.tape.dialect);
+        Ok(())
     }
 
-    fn set_index(&mut self, new_index: usize) {
-        self.index = new_index;
+    fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), RuntimeError> {
+        let mut context = RunContext::new(&mut self.tape, input, output, &self.limits);
+        let result = self
+            .instructions
+            .iter()
+            .try_for_each(|instruction| execute(&mut context, instruction));
+        context.flush_output();
+        result
     }
-}
 
-impl BrainfuckMemory {
-    pub fn new() -> BrainfuckMemory {
-        BrainfuckMemory {
-            index: 0,
-            memory: vec![0; 1000],
-        }
+    fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
     }
 
-    fn interpret_token(&mut self, token: &Token) {
-        match &token.token_type {
-            TokenType::PointerIncrement => {
-                if self.memory.len() - self.index == 1 {
-                    self.memory.resize(self.memory.len() * 2, 0);
-                }
-                self.index += 1;
-            }
-            TokenType::PointerDecrement => {
-                if self.index == 0 {
-                    panic!("Cannot decrease pointer when pointer index = 0.");
-                }
-                self.index -= 1;
-            }
-            TokenType::Increment => {
-                if self.memory[self.index] == u8::MAX {
-                    self.memory[self.index] = u8::MIN;
-                } else {
-                    self.memory[self.index] += 1;
-                }
-            }
-            TokenType::Decrement => {
-                if self.memory[self.index] == u8::MIN {
-                    self.memory[self.index] = u8::MAX;
-                } else {
-                    self.memory[self.index] -= 1;
+    fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    fn tape_mut(&mut self) -> &mut Tape {
+        &mut self.tape
+    }
+}
+
+fn execute(context: &mut RunContext, instruction: &Instruction) -> Result<(), RuntimeError> {
+    let error = |kind| RuntimeError::spanning(kind, &instruction.ranges);
+    context.step(1).map_err(error)?;
+    let tape = &mut *context.tape;
+    match &instruction.op {
+        Op::Add(n) => tape.add_cell(tape.index, *n as i64).map_err(error)?,
+        Op::Move(n) => {
+            tape.index = tape.offset_index(*n).map_err(error)?;
+        }
+        Op::Output => context.output_cell(context.tape.index),
+        Op::Input => context.input_cell(context.tape.index).map_err(error)?,
+        Op::Loop(body) => {
+            while context.tape.current_cell() != 0 {
+                for instruction in body {
+                    execute(context, instruction)?;
                 }
+                // each further condition check is a step of its own
+                context.step(1).map_err(error)?;
             }
-            TokenType::Output => {
-                let c: char = self.memory[self.index].into();
-                print!("{}", c);
+        }
+        Op::SetZero => tape.set_cell(tape.index, 0),
+        Op::MulAdd(offset, factor) => {
+            let value = tape.current_cell();
+            if value != 0 {
+                let target = tape.offset_index(*offset).map_err(error)?;
+                tape.add_cell(target, value as i64 * *factor as i64)
+                    .map_err(error)?;
             }
-            TokenType::Input => {
-                self.memory[self.index] = std::io::stdin().bytes().next().unwrap().unwrap();
+        }
+        Op::ScanRight => {
+            while context.tape.current_cell() != 0 {
+                context.tape.index = context.tape.offset_index(1).map_err(error)?;
+                context.step(1).map_err(error)?;
             }
-            TokenType::SubGroup(sg) => {
-                while self.memory[self.index] != 0 {
-                    for token in sg.tokens().into_iter() {
-                        self.interpret_token(token);
-                    }
-                }
+        }
+        Op::ScanLeft => {
+            while context.tape.current_cell() != 0 {
+                context.tape.index = context.tape.offset_index(-1).map_err(error)?;
+                context.step(1).map_err(error)?;
             }
-            _ => (),
         }
     }
+    Ok(())
 }
 
-pub fn interpret(input: &str) {
-    let parse_result = parse(input).unwrap();
+pub fn interpret(
+    input: &str,
+    dialect: Dialect,
+    mut reader: impl Read,
+    mut writer: impl Write,
+) -> Result<(), ExecutionError> {
+    let mut engine = InterpreterEngine::new(dialect);
+    engine.prepare(input)?;
+    engine.run(&mut reader, &mut writer)?;
+    Ok(())
+}
 
-    let iter = parse_result.parse_token_group.tokens().into_iter();
+#[cfg(test)]
+fn interpret_tape(input: &str, dialect: Dialect) -> Tape {
+    let mut engine = InterpreterEngine::new(dialect);
+    engine.prepare(input).unwrap();
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    engine.tape
+}
 
-    let mut memory = BrainfuckMemory::new();
-    for token in iter {
-        memory.interpret_token(token);
-    }
+#[test]
+pub fn test_interpret_with_idioms() {
+    let tape = interpret_tape("+++[->++>+<<]>[-]>[<]>>>>++[-<<+>>]", Dialect::default());
+    assert_eq!(vec![0, 0, 3, 2, 0], tape.memory[..5].to_vec());
+    assert_eq!(5, tape.index);
+}
+
+#[test]
+pub fn test_interpret_with_wide_cells() {
+    let tape = interpret_tape(
+        "-[->+<]>+++",
+        Dialect {
+            cell_width: crate::CellWidth::Bits16,
+            ..Default::default()
+        },
+    );
+    assert_eq!(0, tape.cell(0));
+    assert_eq!(2, tape.cell(1));
+    assert_eq!(vec![0, 0, 2, 0], tape.memory[..4].to_vec());
+}
+
+#[cfg(test)]
+use {
+    crate::error::RuntimeErrorKind,
+    brainfuck_analyzer::{Position, Range},
+};
+
+#[test]
+pub fn test_interpret_overflow_error() {
+    let result = interpret(
+        "+\n+-[-]-",
+        Dialect {
+            overflow: crate::OverflowBehaviour::Error,
+            ..Default::default()
+        },
+        std::io::empty(),
+        std::io::sink(),
+    );
+    let range = Range::new(Position::new(1, 5), Position::new(1, 6));
+    assert_eq!(
+        Err(ExecutionError::Runtime(RuntimeError {
+            kind: RuntimeErrorKind::CellOverflow,
+            range
+        })),
+        result
+    );
+}
+
+#[test]
+pub fn test_interpret_pointer_underflow() {
+    let result = interpret("><<", Dialect::default(), std::io::empty(), std::io::sink());
+    // `<<` is folded into one move, the error covers all of it
+    let range = Range::new(Position::new(0, 1), Position::new(0, 3));
+    assert_eq!(
+        Err(ExecutionError::Runtime(RuntimeError {
+            kind: RuntimeErrorKind::PointerUnderflow,
+            range
+        })),
+        result
+    );
+}
+
+#[test]
+pub fn test_interpret_pointer_underflow_turning_around() {
+    // `<>` is not folded away, the `<` fails on the starting cell
+    let result = interpret("+<>", Dialect::default(), std::io::empty(), std::io::sink());
+    let range = Range::new(Position::new(0, 1), Position::new(0, 2));
+    assert_eq!(
+        Err(ExecutionError::Runtime(RuntimeError {
+            kind: RuntimeErrorKind::PointerUnderflow,
+            range
+        })),
+        result
+    );
+}
+
+#[test]
+pub fn test_interpret_with_io() {
+    let mut output = Vec::new();
+    let dialect = Dialect {
+        eof: crate::EofBehaviour::Zero,
+        ..Default::default()
+    };
+    interpret(",[+.,]-.", dialect, "abc".as_bytes(), &mut output).unwrap();
+    assert_eq!(b"bcd\xff".to_vec(), output);
+}
+
+#[test]
+pub fn test_interpret_bidirectional_tape() {
+    let dialect = Dialect {
+        tape: crate::TapeGrowth::Both,
+        ..Default::default()
+    };
+    let tape = interpret_tape("+<<++[->>>+<<<]<+[<]", dialect);
+    assert_eq!(-4, tape.position());
+    assert_eq!(2, tape.cell(tape.origin + 1));
+    assert_eq!(1, tape.cell(tape.origin));
+    assert_eq!(1, tape.cell(tape.origin - 3));
 }
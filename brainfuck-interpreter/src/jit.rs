@@ -1,297 +1,1423 @@
+use std::io::{Read, Write};
 use std::mem::transmute;
 
-use crate::interpreter::BrainfuckMemory;
+use crate::code_cache::CodeCache;
+use crate::dialect::{CellWidth, Dialect, OverflowBehaviour, TapeGrowth};
+use crate::elf::ElfRuntime;
+use crate::engine::{Engine, Limits, RunContext, OUTPUT_BUFFER_BYTES};
+use crate::error::{span, ExecutionError, RuntimeError, RuntimeErrorKind};
+use crate::tape::Tape;
 use assembler::mnemonic_parameter_types::memory::{Memory, MemoryOperand};
+use assembler::mnemonic_parameter_types::registers::Register16Bit::AX;
+use assembler::mnemonic_parameter_types::registers::Register32Bit;
+use assembler::mnemonic_parameter_types::registers::Register32Bit::{EAX, EDX, R8D};
 use assembler::mnemonic_parameter_types::registers::Register64Bit::*;
 use assembler::mnemonic_parameter_types::registers::Register8Bit::*;
+use assembler::mnemonic_parameter_types::Label;
 use assembler::ExecutableAnonymousMemoryMap::ExecutableAnonymousMemoryMap;
 use assembler::InstructionStream::InstructionStream;
 use assembler::InstructionStreamHints::InstructionStreamHints;
-use brainfuck_analyzer::{parse, TokenGroup, TokenType};
+use brainfuck_analyzer::{parse, Instruction, Op, ParseError, Position, Range, TokenGroup};
+
+type JITFunction =
+    unsafe extern "sysv64" fn(mem: *const u8, offset: u64, context: *mut u8) -> JITExit;
 
 pub struct JITCache {
     #[allow(unused_variables, dead_code)]
-    function_pointer:
-        unsafe extern "sysv64" fn(mem: *const u8, offset: u64, struct_ptr: *const u8) -> u64,
+    function_pointer: JITFunction,
     #[allow(unused_variables, dead_code)]
     memory_map: ExecutableAnonymousMemoryMap,
+    dialect: Dialect,
+    source_map: SourceMap,
+    // bytes of code starting at function_pointer
+    code_size: usize,
 }
 unsafe impl Send for JITCache {}
 
-pub trait IBrainfuckRuntime {
-    fn get_memory_vec_ptr(&self) -> *const u8;
-    fn get_index(&self) -> usize;
-    fn set_index(&mut self, new_index: usize);
+impl JITCache {
+    /// The generated machine code.
+    pub fn code(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.function_pointer as *const u8, self.code_size) }
+    }
+
+    /// Offsets into `code` where the code of a source range starts, in order. `None` marks code no
+    /// instruction produced.
+    pub fn code_blocks(&self) -> &[(usize, Option<Range>)] {
+        &self.source_map.blocks
+    }
+
+    pub(crate) fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+}
+
+// Ties compiled code back to the source.
+#[derive(Default)]
+pub(crate) struct SourceMap {
+    // source range of every instruction, exit statuses refer to them by index
+    pub sites: Vec<Range>,
+    // see JITCache::code_blocks, offsets count from the start of the instruction stream
+    pub blocks: Vec<(usize, Option<Range>)>,
+}
+
+// Two integers are returned in RAX and RDX: the final memory offset and why the code stopped.
+#[repr(C)]
+struct JITExit {
+    offset: u64,
+    status: u64,
+}
+
+// Returned by runtime_reserve in RAX and RDX: the memory head, null on failure, and the bytes added
+// on the left of the memory, which the current offset has to move by.
+#[repr(C)]
+struct JITReserve {
+    memory: *const u8,
+    shift: u64,
+}
+
+// What compiled code runs in, which decides how it does I/O, grows the tape and stops.
+#[derive(Clone, Copy)]
+pub(crate) enum Target {
+    // Called by `run` with a RunContext, I/O and tape growth go through rust functions.
+    Jit,
+    // A standalone executable talking to the kernel itself, see elf.rs.
+    Elf(ElfRuntime),
+}
+
+// The low byte of a status is one of these, the remaining bits are the index of the site that stopped.
+const JIT_EXIT_COMPLETED: u32 = 0;
+// A cell overflowed and the dialect treats overflow as an error.
+const JIT_EXIT_OVERFLOW: u32 = 1;
+const JIT_EXIT_UNDERFLOW: u32 = 2;
+// A runtime function failed and left the reason in RunContext.failure.
+const JIT_EXIT_CALL_FAILED: u32 = 3;
+
+// Version of the code `compile` generates and of the RunContext layout it expects. Bump it with
+// every change to either, code cached by an older version must not be loaded.
+pub(crate) const CODE_VERSION: u32 = 2;
+
+/// Compiles the whole program into x86_64 code before running it.
+pub struct JITEngine {
+    jit_cache: Option<JITCache>,
+    tape: Tape,
+    limits: Limits,
+    code_cache: Option<CodeCache>,
+}
+
+impl JITEngine {
+    pub fn new(dialect: Dialect) -> JITEngine {
+        JITEngine {
+            jit_cache: None,
+            tape: Tape::new(dialect),
+            limits: Limits::default(),
+            code_cache: None,
+        }
+    }
+
+    /// Keep the compiled program in `code_cache` and load it from there when it was compiled before.
+    pub fn set_code_cache(&mut self, code_cache: Option<CodeCache>) {
+        self.code_cache = code_cache;
+    }
+}
+
+impl Engine for JITEngine {
+    fn prepare(&mut self, source: &str) -> Result<(), ParseError> {
+        let parse_result = parse(source)?;
+        let dialect = self.tape.dialect;
+        self.jit_cache = Some(match &self.code_cache {
+            Some(code_cache) => {
+                code_cache.compile(source, &parse_result.parse_token_group, dialect)
+            }
+            None => compile(&parse_result.parse_token_group, dialect),
+        });
+        self.tape = Tape::new(self.tape.dialect);
+        Ok(())
+    }
+
+    fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), RuntimeError> {
+        let mut context = RunContext::new(&mut self.tape, input, output, &self.limits);
+        let result = match &self.jit_cache {
+            Some(jit_cache) => run(jit_cache, &mut context),
+            None => Ok(()),
+        };
+        context.flush_output();
+        result
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    fn tape_mut(&mut self) -> &mut Tape {
+        &mut self.tape
+    }
 }
 
-pub fn compile(input: &TokenGroup) -> JITCache {
-    // TODO: should support memory allocation increasement
-    let mut memory_map =
-        ExecutableAnonymousMemoryMap::new(4096, false, true).expect("Could not anonymously mmap");
+pub fn compile(input: &TokenGroup, dialect: Dialect) -> JITCache {
+    let instructions = dialect.instructions(input);
+    // room for the alignment, the instructions and the final exit
+    let map_size = 64 + code_size_bound(&instructions) + MAX_SEQUENCE_BYTES;
+    let mut memory_map = ExecutableAnonymousMemoryMap::new(map_size, false, true)
+        .expect("Could not anonymously mmap");
 
     let mut instruction_stream = memory_map.instruction_stream(&InstructionStreamHints::default());
 
     instruction_stream.emit_alignment(64);
 
     // use current position as fuction_pointer_head
-    let function_pointer_head: unsafe extern "sysv64" fn(
-        mem: *const u8,
-        offset: u64,
-        struct_ptr: *const u8,
-    ) -> u64 = unsafe {
+    let function_pointer_head: JITFunction = unsafe {
         transmute(instruction_stream.ternary_function_pointer::<u64, *const u8, u64, *const u8>())
     };
 
-    _compile(input, &mut instruction_stream);
+    let function_start = instruction_stream.bytes_emitted();
 
-    // copy offset into return value
-    instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RAX, RSI);
+    let mut source_map = SourceMap::default();
+    _compile(
+        &instructions,
+        &mut instruction_stream,
+        dialect,
+        Target::Jit,
+        &mut source_map,
+    );
 
-    // Caller should clean up stack. So just pop rip and jump the this address.
-    // ref data: https://en.wikipedia.org/wiki/X86_calling_conventions
-    // #List of x86 calling conventions #System V AMD64 ABI
-    instruction_stream.ret();
+    source_map
+        .blocks
+        .push((instruction_stream.bytes_emitted(), None));
+    emit_exit(&mut instruction_stream, JIT_EXIT_COMPLETED);
 
-    instruction_stream.finish();
+    let (code, _) = instruction_stream.finish();
+    let code_size = code.len() - function_start;
+    for (offset, _) in &mut source_map.blocks {
+        *offset -= function_start;
+    }
 
     JITCache {
         function_pointer: function_pointer_head,
         memory_map,
+        dialect,
+        source_map,
+        code_size,
+    }
+}
+
+// Copy code `compile` generated before, maybe in another process, into executable memory.
+pub(crate) fn load(code: &[u8], source_map: SourceMap, dialect: Dialect) -> JITCache {
+    let mut memory_map = ExecutableAnonymousMemoryMap::new(64 + code.len(), false, true)
+        .expect("Could not anonymously mmap");
+
+    let mut instruction_stream = memory_map.instruction_stream(&InstructionStreamHints::default());
+    instruction_stream.emit_alignment(64);
+    let function_pointer: JITFunction = unsafe {
+        transmute(instruction_stream.ternary_function_pointer::<u64, *const u8, u64, *const u8>())
+    };
+    instruction_stream.emit_bytes(code);
+    instruction_stream.finish();
+
+    JITCache {
+        function_pointer,
+        memory_map,
+        dialect,
+        source_map,
+        code_size: code.len(),
     }
 }
 
-fn _compile(input: &TokenGroup, instruction_stream: &mut InstructionStream) {
+// More than any fixed sequence of code emitted for one instruction takes. Growing the memory
+// through runtime_reserve, followed by the check of the call, is the longest.
+pub(crate) const MAX_SEQUENCE_BYTES: usize = 128;
+
+// An upper bound of the code `_compile` emits for `instructions`, to size the executable memory.
+pub(crate) fn code_size_bound(instructions: &[Instruction]) -> usize {
+    instructions
+        .iter()
+        .map(|instruction| match &instruction.op {
+            Op::Loop(body) => 2 * MAX_SEQUENCE_BYTES + code_size_bound(body),
+            Op::ScanRight | Op::ScanLeft => 3 * MAX_SEQUENCE_BYTES,
+            // Straight runs may be emitted twice, behind a bounds check. A multiplication also checks
+            // its target cell.
+            Op::MulAdd(..) => 5 * MAX_SEQUENCE_BYTES,
+            _ => 3 * MAX_SEQUENCE_BYTES,
+        })
+        .sum()
+}
+
+pub(crate) fn _compile(
+    input: &[Instruction],
+    instruction_stream: &mut InstructionStream,
+    dialect: Dialect,
+    target: Target,
+    source_map: &mut SourceMap,
+) {
     // RDI pointer to the head of brainfuck memory(vec part)
-    // RSI = current offset in brainfuck memory
-    // RDX = pointer to the head of BrainfuckMemory struct
+    // RSI = current offset in brainfuck memory, in bytes. A cell takes `width` bytes.
+    // RDX = pointer to the RunContext struct
+    // Only registers the System V ABI lets a function clobber are used, the caller expects nothing back.
     // ref data: https://github.com/phip1611/rust-different-calling-conventions-example
-    for t in input.tokens().into_iter() {
-        match &t.token_type {
-            TokenType::PointerDecrement => instruction_stream.dec_Register64Bit(RSI),
-            TokenType::PointerIncrement => {
-                // jump to rust function runtime_resize, rust function will resize runtime memory if needed
-                // push stack: RSI, RDX
-                // here we don't really push RDI, because the head of brainfuck memory(vec part) may change after resize. Always use fn return value as RDI.
-                instruction_stream.push_Register64Bit_r64(RSI);
-                instruction_stream.push_Register64Bit_r64(RDX);
-
-                // move runtime(RDX) to RDI(the first param of function runtime_resize)
-                instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RDI, RDX);
-                // [NO CODE] move RSI to RSI
-
-                // call function
-                let fn_ptr: u64 = unsafe {
-                    transmute::<
-                        unsafe extern "sysv64" fn(
-                            runtime: &mut BrainfuckMemory,
-                            current_index: u8,
-                        ) -> *const u8,
-                        u64,
-                    >(runtime_resize)
-                };
-                instruction_stream.mov_Register64Bit_Immediate64Bit(RAX, fn_ptr.into());
-                instruction_stream.call_Register64Bit(RAX);
+    let width = dialect.cell_width.bytes() as isize;
+    let mut rest = input;
+    while !rest.is_empty() {
+        // a loop on its own, or the straight run of instructions up to the next one
+        let length = rest
+            .iter()
+            .position(|instruction| {
+                matches!(instruction.op, Op::Loop(_) | Op::ScanRight | Op::ScanLeft)
+            })
+            .unwrap_or(rest.len());
+        let (run, tail) = rest.split_at(length.max(1));
+        rest = tail;
+
+        // checks emitted for an instruction exit with its site
+        let first_site = source_map.sites.len() as u32;
+        source_map
+            .sites
+            .extend(run.iter().map(|instruction| span(&instruction.ranges)));
 
-                //pop stack: RSI, RDI
-                instruction_stream.pop_Register64Bit_r64(RDX);
-                instruction_stream.pop_Register64Bit_r64(RSI);
-                // here we don't really pop RDI. Always use fn return value as RDI.
-                instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RDI, RAX);
+        let bounds = match target {
+            Target::Jit => run_bounds(run, width),
+            Target::Elf(_) => None,
+        };
+        let Some((lowest, highest)) = bounds else {
+            _compile_run(
+                run,
+                instruction_stream,
+                dialect,
+                target,
+                source_map,
+                first_site,
+                true,
+            );
+            continue;
+        };
 
-                instruction_stream.inc_Register64Bit(RSI);
+        // When every cell the run touches is already usable, run it without checking the pointer.
+        // Otherwise the checked copy grows the memory or fails at the same instruction as always.
+        let checked_label = instruction_stream.create_label();
+        let end_label = instruction_stream.create_label();
+        let ranges = [span(&run[0].ranges), span(&run[run.len() - 1].ranges)];
+        source_map
+            .blocks
+            .push((instruction_stream.bytes_emitted(), Some(span(&ranges))));
+        emit_bounds_check(instruction_stream, lowest, highest, checked_label);
+        _compile_run(
+            run,
+            instruction_stream,
+            dialect,
+            target,
+            source_map,
+            first_site,
+            false,
+        );
+        instruction_stream.jmp_Label_1(end_label);
+        instruction_stream.attach_label(checked_label);
+        _compile_run(
+            run,
+            instruction_stream,
+            dialect,
+            target,
+            source_map,
+            first_site,
+            true,
+        );
+        instruction_stream.attach_label(end_label);
+    }
+}
+
+// The lowest and highest byte a straight run touches, relative to the offset it starts at. `None`
+// when the run checks the pointer less than twice, checking the bounds up front would not pay off.
+fn run_bounds(run: &[Instruction], width: isize) -> Option<(isize, isize)> {
+    let mut offset = 0;
+    let mut bounds = (0, width - 1);
+    let mut checks = 0;
+    for instruction in run {
+        let cell = match instruction.op {
+            Op::Move(n) => {
+                offset += n * width;
+                offset
             }
-            TokenType::Decrement => instruction_stream.sub_Any8BitMemory_Immediate8Bit(
-                MemoryOperand::base_64_index_64(RDI, RSI).into(),
-                1u8.into(),
-            ),
-            TokenType::Increment => instruction_stream.add_Any8BitMemory_Immediate8Bit(
-                MemoryOperand::base_64_index_64(RDI, RSI).into(),
-                1u8.into(),
-            ),
-            TokenType::Output => {
-                // push RDI, RSI, RDX
-                instruction_stream.push_Register64Bit_r64(RDI);
-                instruction_stream.push_Register64Bit_r64(RSI);
-                instruction_stream.push_Register64Bit_r64(RDX);
-
-                // move RDI+RSI value (the char for print) to RDI
-                // pub fn mov_Register64Bit_Any64BitMemory(&mut self, dist: Register64Bit, src: Any64BitMemory) // function name format <behavior>_<dist>_<src>
-                instruction_stream.mov_Register64Bit_Any64BitMemory(
-                    RDI,
-                    MemoryOperand::base_64_index_64(RDI, RSI).into(),
-                );
-                // call function, input use RDI number
-                let fn_ptr: u64 = unsafe {
-                    transmute::<unsafe extern "sysv64" fn(c: u8) -> u8, u64>(output_char)
-                };
-                instruction_stream.mov_Register64Bit_Immediate64Bit(RAX, fn_ptr.into());
-                instruction_stream.call_Register64Bit(RAX);
+            Op::MulAdd(cell_offset, _) => offset + cell_offset * width,
+            _ => continue,
+        };
+        checks += 1;
+        bounds = (bounds.0.min(cell), bounds.1.max(cell + width - 1));
+    }
+    (checks >= 2).then_some(bounds)
+}
+
+// Jump to `checked_label` unless the bytes from RSI + lowest to RSI + highest are all between
+// RunContext.memory_start and memory_end, the third and second fields. The comparisons are signed,
+// a run may reach left of the memory.
+fn emit_bounds_check(
+    instruction_stream: &mut InstructionStream,
+    lowest: isize,
+    highest: isize,
+    checked_label: Label,
+) {
+    for (offset, field) in [(lowest, 16), (highest, 8)] {
+        instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RAX, RSI);
+        instruction_stream.add_Register64Bit_Immediate32Bit(RAX, (offset as i32).into());
+        instruction_stream.mov_Register64Bit_Any64BitMemory(
+            RCX,
+            MemoryOperand::base_64_displacement(RDX, field.into()).into(),
+        );
+        instruction_stream.cmp_Register64Bit_Register64Bit_r64_rm64(RAX, RCX);
+        if field == 16 {
+            instruction_stream.jl_Label_1(checked_label);
+        } else {
+            instruction_stream.jge_Label_1(checked_label);
+        }
+    }
+}
 
-                //pop RDX, RSI, RDI
-                instruction_stream.pop_Register64Bit_r64(RDX);
-                instruction_stream.pop_Register64Bit_r64(RSI);
-                instruction_stream.pop_Register64Bit_r64(RDI);
+// Compile the instructions of `run`, the first one has `first_site`. Without `checked` the pointer
+// moves and cells are reached without looking at the bounds, see `_compile`.
+fn _compile_run(
+    run: &[Instruction],
+    instruction_stream: &mut InstructionStream,
+    dialect: Dialect,
+    target: Target,
+    source_map: &mut SourceMap,
+    first_site: u32,
+    checked: bool,
+) {
+    let width = dialect.cell_width.bytes() as isize;
+    let cell = || MemoryOperand::base_64_index_64(RDI, RSI);
+    for (site, instruction) in (first_site..).zip(run) {
+        let range = source_map.sites[site as usize];
+        source_map
+            .blocks
+            .push((instruction_stream.bytes_emitted(), Some(range)));
+        match &instruction.op {
+            Op::Move(n) if !checked => instruction_stream
+                .add_Register64Bit_Immediate32Bit(RSI, ((n * width) as i32).into()),
+            Op::Move(n) if *n < 0 => {
+                emit_pointer_decrease(instruction_stream, target, dialect.tape, -n * width, site)
             }
-            TokenType::Input => {
-                // push RDI, RSI, RDX
-                instruction_stream.push_Register64Bit_r64(RDI);
-                instruction_stream.push_Register64Bit_r64(RSI);
-                instruction_stream.push_Register64Bit_r64(RDX);
-
-                // call function, return value will be saved into RAX
-                // Integer return values up to 64 bits in size are stored in RAX, ref data: https://en.wikipedia.org/wiki/X86_calling_conventions
-                let fn_ptr: u64 =
-                    unsafe { transmute::<unsafe extern "sysv64" fn() -> u8, u64>(input_char) };
-                instruction_stream.mov_Register64Bit_Immediate64Bit(RAX, fn_ptr.into());
-                instruction_stream.call_Register64Bit(RAX);
-
-                //pop RDX, RSI, RDI
-                instruction_stream.pop_Register64Bit_r64(RDX);
-                instruction_stream.pop_Register64Bit_r64(RSI);
-                instruction_stream.pop_Register64Bit_r64(RDI);
-
-                // move input char (RAX) into RDI+RSI
-                instruction_stream.mov_Any64BitMemory_Register64Bit(
-                    MemoryOperand::base_64_index_64(RDI, RSI).into(),
-                    RAX,
-                );
+            Op::Move(n) => {
+                emit_pointer_increase(instruction_stream, target, n * width, width, site)
+            }
+            Op::Add(n) => {
+                emit_cell_add_immediate(instruction_stream, target, dialect, cell(), *n, site)
             }
-            TokenType::SubGroup(sg) => {
+            Op::Output => match target {
+                Target::Jit => emit_buffered_output(instruction_stream, site),
+                Target::Elf(runtime) => runtime.emit_output(instruction_stream),
+            },
+            Op::Input => match target {
+                Target::Jit => emit_runtime_call(instruction_stream, RUNTIME_INPUT, site),
+                Target::Elf(runtime) => runtime.emit_input(instruction_stream, dialect),
+            },
+            Op::Loop(body) => {
                 let loop_start_label = instruction_stream.create_and_attach_label();
                 let loop_end_label = instruction_stream.create_label();
 
-                // If the byte at the data pointer != zero, start loop
-                instruction_stream.mov_Register8Bit_Any8BitMemory(
-                    AL,
-                    MemoryOperand::base_64_index_64(RDI, RSI).into(),
-                );
-                instruction_stream.cmp_Register8Bit_Immediate8Bit(AL, 0u8.into());
+                // If the cell at the data pointer != zero, start loop
+                emit_cell_compare_zero(instruction_stream, dialect.cell_width, cell());
                 instruction_stream.jz_Label_1(loop_end_label);
 
                 // loop part
-                _compile(&sg, instruction_stream);
+                _compile(body, instruction_stream, dialect, target, source_map);
+                // the rest belongs to the closing bracket
+                let close = Position::new(range.end.line, range.end.character.saturating_sub(1));
+                source_map.blocks.push((
+                    instruction_stream.bytes_emitted(),
+                    Some(Range::new(close, range.end)),
+                ));
+                // one step for each instruction of the body and one for the next condition check,
+                // executables run without limits
+                if let Target::Jit = target {
+                    emit_step_check(instruction_stream, body.len() as u32 + 1, site);
+                }
 
                 // jump to "["
                 instruction_stream.jmp_Label_1(loop_start_label);
                 instruction_stream.attach_label(loop_end_label);
             }
-            _ => (),
+            Op::SetZero => {
+                match dialect.cell_width {
+                    CellWidth::Bits8 => instruction_stream
+                        .mov_Any8BitMemory_Immediate8Bit(cell().into(), 0u8.into()),
+                    CellWidth::Bits16 => instruction_stream
+                        .mov_Any16BitMemory_Immediate16Bit(cell().into(), 0u16.into()),
+                    CellWidth::Bits32 => instruction_stream
+                        .mov_Any32BitMemory_Immediate32Bit(cell().into(), 0u32.into()),
+                }
+            }
+            Op::MulAdd(offset, factor) => {
+                let skip_label = instruction_stream.create_label();
+                emit_cell_compare_zero(instruction_stream, dialect.cell_width, cell());
+                instruction_stream.jz_Label_1(skip_label);
+
+                // unchecked, the bounds of the run cover the target cell
+                if checked && *offset > 0 {
+                    emit_memory_reserve(
+                        instruction_stream,
+                        target,
+                        offset * width + width - 1,
+                        site,
+                    );
+                } else if checked && dialect.tape == TapeGrowth::Both {
+                    emit_memory_reserve(instruction_stream, target, offset * width, site);
+                }
+
+                // RCX = offset of the target cell
+                instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RCX, RSI);
+                if *offset > 0 {
+                    instruction_stream
+                        .add_Register64Bit_Immediate32Bit(RCX, ((offset * width) as i32).into());
+                } else if dialect.tape == TapeGrowth::Both || !checked {
+                    instruction_stream
+                        .sub_Register64Bit_Immediate32Bit(RCX, ((-offset * width) as i32).into());
+                } else {
+                    // the target must not be left of the first cell
+                    let in_range_label = instruction_stream.create_label();
+                    instruction_stream
+                        .sub_Register64Bit_Immediate32Bit(RCX, ((-offset * width) as i32).into());
+                    instruction_stream.jae_Label(in_range_label);
+                    emit_failure(
+                        instruction_stream,
+                        target,
+                        RuntimeErrorKind::PointerUnderflow,
+                        site,
+                    );
+                    instruction_stream.attach_label(in_range_label);
+                }
+                let target_cell = MemoryOperand::base_64_index_64(RDI, RCX);
+
+                // RAX = current cell * factor, a cell times an i32 always fits
+                emit_cell_load(instruction_stream, dialect.cell_width, EAX, cell());
+                instruction_stream.imul_Register64Bit_Register64Bit_Immediate32Bit(
+                    RAX,
+                    RAX,
+                    (*factor).into(),
+                );
+                match dialect.overflow {
+                    // the low bits of the product are all a wrapping cell keeps
+                    OverflowBehaviour::Wrap => match dialect.cell_width {
+                        CellWidth::Bits8 => instruction_stream
+                            .add_Any8BitMemory_Register8Bit(target_cell.into(), AL),
+                        CellWidth::Bits16 => instruction_stream
+                            .add_Any16BitMemory_Register16Bit(target_cell.into(), AX),
+                        CellWidth::Bits32 => instruction_stream
+                            .add_Any32BitMemory_Register32Bit(target_cell.into(), EAX),
+                    },
+                    // add in 64 bits, a result below zero compares above the maximum as well
+                    OverflowBehaviour::Error => {
+                        let in_range_label = instruction_stream.create_label();
+                        emit_cell_load(instruction_stream, dialect.cell_width, R8D, target_cell);
+                        instruction_stream.add_Register64Bit_Register64Bit_r64_rm64(RAX, R8);
+                        instruction_stream.mov_Register32Bit_Immediate32Bit(
+                            R8D,
+                            dialect.cell_width.max_value().into(),
+                        );
+                        instruction_stream.cmp_Register64Bit_Register64Bit_r64_rm64(RAX, R8);
+                        instruction_stream.jbe_Label(in_range_label);
+                        emit_failure(
+                            instruction_stream,
+                            target,
+                            RuntimeErrorKind::CellOverflow,
+                            site,
+                        );
+                        instruction_stream.attach_label(in_range_label);
+                        match dialect.cell_width {
+                            CellWidth::Bits8 => instruction_stream
+                                .mov_Any8BitMemory_Register8Bit(target_cell.into(), AL),
+                            CellWidth::Bits16 => instruction_stream
+                                .mov_Any16BitMemory_Register16Bit(target_cell.into(), AX),
+                            CellWidth::Bits32 => instruction_stream
+                                .mov_Any32BitMemory_Register32Bit(target_cell.into(), EAX),
+                        }
+                    }
+                }
+                instruction_stream.attach_label(skip_label);
+            }
+            Op::ScanRight | Op::ScanLeft => {
+                let scan_start_label = instruction_stream.create_and_attach_label();
+                let scan_end_label = instruction_stream.create_label();
+
+                emit_cell_compare_zero(instruction_stream, dialect.cell_width, cell());
+                instruction_stream.jz_Label_1(scan_end_label);
+
+                if instruction.op == Op::ScanRight {
+                    emit_pointer_increase(instruction_stream, target, width, width, site);
+                } else {
+                    emit_pointer_decrease(instruction_stream, target, dialect.tape, width, site);
+                }
+                // one step for each move, as the other engines count them
+                if let Target::Jit = target {
+                    emit_step_check(instruction_stream, 1, site);
+                }
+
+                instruction_stream.jmp_Label_1(scan_start_label);
+                instruction_stream.attach_label(scan_end_label);
+            }
+        }
+    }
+}
+
+// Load the cell zero extended into `register`, which clears the upper half of its 64 bit register.
+fn emit_cell_load(
+    instruction_stream: &mut InstructionStream,
+    cell_width: CellWidth,
+    register: Register32Bit,
+    cell: MemoryOperand,
+) {
+    match cell_width {
+        CellWidth::Bits8 => {
+            instruction_stream.movzx_Register32Bit_Any8BitMemory(register, cell.into())
+        }
+        CellWidth::Bits16 => {
+            instruction_stream.movzx_Register32Bit_Any16BitMemory(register, cell.into())
         }
+        CellWidth::Bits32 => {
+            instruction_stream.mov_Register32Bit_Any32BitMemory(register, cell.into())
+        }
+    }
+}
+
+fn emit_cell_compare_zero(
+    instruction_stream: &mut InstructionStream,
+    cell_width: CellWidth,
+    cell: MemoryOperand,
+) {
+    match cell_width {
+        CellWidth::Bits8 => {
+            instruction_stream.cmp_Any8BitMemory_Immediate8Bit(cell.into(), 0u8.into())
+        }
+        CellWidth::Bits16 => {
+            instruction_stream.cmp_Any16BitMemory_Immediate16Bit(cell.into(), 0u16.into())
+        }
+        CellWidth::Bits32 => {
+            instruction_stream.cmp_Any32BitMemory_Immediate32Bit(cell.into(), 0u32.into())
+        }
+    }
+}
+
+// Add `n` to the cell. Negative values use sub, so the carry flag reports overflow in both directions.
+fn emit_cell_add_immediate(
+    instruction_stream: &mut InstructionStream,
+    target: Target,
+    dialect: Dialect,
+    cell: MemoryOperand,
+    n: i32,
+    site: u32,
+) {
+    let mut magnitude = n.unsigned_abs();
+    if magnitude > dialect.cell_width.max_value() {
+        if dialect.overflow == OverflowBehaviour::Error {
+            // no cell value survives this add
+            emit_failure(
+                instruction_stream,
+                target,
+                RuntimeErrorKind::CellOverflow,
+                site,
+            );
+            return;
+        }
+        magnitude %= dialect.cell_width.max_value() + 1;
+    }
+
+    match (dialect.cell_width, n > 0) {
+        (CellWidth::Bits8, true) => instruction_stream
+            .add_Any8BitMemory_Immediate8Bit(cell.into(), (magnitude as u8).into()),
+        (CellWidth::Bits8, false) => instruction_stream
+            .sub_Any8BitMemory_Immediate8Bit(cell.into(), (magnitude as u8).into()),
+        (CellWidth::Bits16, true) => instruction_stream
+            .add_Any16BitMemory_Immediate16Bit(cell.into(), (magnitude as u16).into()),
+        (CellWidth::Bits16, false) => instruction_stream
+            .sub_Any16BitMemory_Immediate16Bit(cell.into(), (magnitude as u16).into()),
+        (CellWidth::Bits32, true) => {
+            instruction_stream.add_Any32BitMemory_Immediate32Bit(cell.into(), magnitude.into())
+        }
+        (CellWidth::Bits32, false) => {
+            instruction_stream.sub_Any32BitMemory_Immediate32Bit(cell.into(), magnitude.into())
+        }
+    }
+    if dialect.overflow == OverflowBehaviour::Error {
+        emit_overflow_check(instruction_stream, target, site);
+    }
+}
+
+// Stop with a cell overflow when the carry flag is set by the previous add or sub.
+fn emit_overflow_check(instruction_stream: &mut InstructionStream, target: Target, site: u32) {
+    let no_overflow_label = instruction_stream.create_label();
+    instruction_stream.jae_Label(no_overflow_label);
+    emit_failure(
+        instruction_stream,
+        target,
+        RuntimeErrorKind::CellOverflow,
+        site,
+    );
+    instruction_stream.attach_label(no_overflow_label);
+}
+
+// Stop the program with an error compiled code detects by itself, everything else is reported by
+// the runtime functions.
+fn emit_failure(
+    instruction_stream: &mut InstructionStream,
+    target: Target,
+    kind: RuntimeErrorKind,
+    site: u32,
+) {
+    match target {
+        Target::Jit => {
+            let reason = match kind {
+                RuntimeErrorKind::CellOverflow => JIT_EXIT_OVERFLOW,
+                RuntimeErrorKind::PointerUnderflow => JIT_EXIT_UNDERFLOW,
+                _ => unreachable!("{:?} is reported by a runtime function", kind),
+            };
+            emit_exit(instruction_stream, exit_status(reason, site));
+        }
+        Target::Elf(runtime) => instruction_stream.jmp_Label_1(runtime.failure_label(kind)),
+    }
+}
+
+// Take `cost` from RunContext.steps_left, the first field of the context. Once it runs out,
+// runtime_refill_steps checks the limits and either refills it or stops the program.
+fn emit_step_check(instruction_stream: &mut InstructionStream, cost: u32, site: u32) {
+    let steps_left_label = instruction_stream.create_label();
+    instruction_stream
+        .sub_Any64BitMemory_Immediate32Bit(MemoryOperand::base_64(RDX).into(), cost.into());
+    instruction_stream.jae_Label(steps_left_label);
+    emit_runtime_call(instruction_stream, RUNTIME_REFILL_STEPS, site);
+    instruction_stream.attach_label(steps_left_label);
+}
+
+// Exit with JIT_EXIT_CALL_FAILED when the runtime function just called returned zero in RAX.
+fn emit_call_check(instruction_stream: &mut InstructionStream, site: u32) {
+    let succeeded_label = instruction_stream.create_label();
+    instruction_stream.cmp_Register64Bit_Immediate32Bit(RAX, 0i32.into());
+    instruction_stream.jnz_Label(succeeded_label);
+    emit_exit(instruction_stream, exit_status(JIT_EXIT_CALL_FAILED, site));
+    instruction_stream.attach_label(succeeded_label);
+}
+
+fn exit_status(reason: u32, site: u32) -> u32 {
+    site << 8 | reason
+}
+
+// Return the offset in RAX and `status` in RDX.
+// Pushes around calls are always popped again, so the stack is balanced anywhere between instructions and ret is safe.
+fn emit_exit(instruction_stream: &mut InstructionStream, status: u32) {
+    instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RAX, RSI);
+    instruction_stream.mov_Register32Bit_Immediate32Bit(EDX, status.into());
+
+    // Caller should clean up stack. So just pop rip and jump the this address.
+    // ref data: https://en.wikipedia.org/wiki/X86_calling_conventions
+    // #List of x86 calling conventions #System V AMD64 ABI
+    instruction_stream.ret();
+}
+
+// Call `function(context, offset)` and exit when it fails. I/O goes through the context, which holds the caller-supplied streams.
+fn emit_runtime_call(instruction_stream: &mut InstructionStream, function: u32, site: u32) {
+    // push RDI, RSI, RDX. Three pushes on top of the return address leave the stack aligned to 16
+    // bytes at the call, as System V requires.
+    instruction_stream.push_Register64Bit_r64(RDI);
+    instruction_stream.push_Register64Bit_r64(RSI);
+    instruction_stream.push_Register64Bit_r64(RDX);
+
+    // move context(RDX) to RDI(the first param), RSI is already the offset
+    instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RDI, RDX);
+    emit_context_call(instruction_stream, function);
+
+    //pop RDX, RSI, RDI
+    instruction_stream.pop_Register64Bit_r64(RDX);
+    instruction_stream.pop_Register64Bit_r64(RSI);
+    instruction_stream.pop_Register64Bit_r64(RDI);
+    emit_call_check(instruction_stream, site);
+}
+
+// Call the runtime function with the index `function`, RDI has to hold the context already.
+fn emit_context_call(instruction_stream: &mut InstructionStream, function: u32) {
+    let address = 24 + 8 * function as i32;
+    instruction_stream.mov_Register64Bit_Any64BitMemory(
+        RAX,
+        MemoryOperand::base_64_displacement(RDI, address.into()).into(),
+    );
+    instruction_stream.call_Register64Bit(RAX);
+}
+
+// Append the low byte of the cell to RunContext.output_buffer, its length is the field at 56 and the
+// buffer follows it. runtime_flush_output writes the buffer out after a newline or once it is full.
+fn emit_buffered_output(instruction_stream: &mut InstructionStream, site: u32) {
+    let length = || MemoryOperand::base_64_displacement(RDX, 56i32.into());
+    let flush_label = instruction_stream.create_label();
+    let done_label = instruction_stream.create_label();
+    instruction_stream
+        .mov_Register8Bit_Any8BitMemory(AL, MemoryOperand::base_64_index_64(RDI, RSI).into());
+    instruction_stream.mov_Register64Bit_Any64BitMemory(RCX, length().into());
+    instruction_stream.mov_Any8BitMemory_Register8Bit(
+        MemoryOperand::base_64_index_64_displacement(RDX, RCX, 64i32.into()).into(),
+        AL,
+    );
+    instruction_stream.add_Any64BitMemory_Immediate32Bit(length().into(), 1i32.into());
+    instruction_stream.cmp_Register8Bit_Immediate8Bit(AL, b'\n'.into());
+    instruction_stream.jz_Label(flush_label);
+    instruction_stream
+        .cmp_Any64BitMemory_Immediate32Bit(length().into(), (OUTPUT_BUFFER_BYTES as i32).into());
+    instruction_stream.jb_Label(done_label);
+    instruction_stream.attach_label(flush_label);
+    emit_runtime_call(instruction_stream, RUNTIME_FLUSH_OUTPUT, site);
+    instruction_stream.attach_label(done_label);
+}
+
+// Move RSI `bytes` to the right, checking only the cell it ends up on.
+fn emit_pointer_increase(
+    instruction_stream: &mut InstructionStream,
+    target: Target,
+    bytes: isize,
+    width: isize,
+    site: u32,
+) {
+    // make sure the whole target cell exists before moving onto it
+    emit_memory_reserve(instruction_stream, target, bytes + width - 1, site);
+    instruction_stream.add_Register64Bit_Immediate32Bit(RSI, (bytes as i32).into());
+}
+
+// Move RSI `bytes` to the left. Passing the first cell grows the memory on a tape growing both ways,
+// otherwise it exits with JIT_EXIT_UNDERFLOW and RSI unchanged.
+fn emit_pointer_decrease(
+    instruction_stream: &mut InstructionStream,
+    target: Target,
+    tape: TapeGrowth,
+    bytes: isize,
+    site: u32,
+) {
+    if tape == TapeGrowth::Both {
+        emit_memory_reserve(instruction_stream, target, -bytes, site);
+        instruction_stream.sub_Register64Bit_Immediate32Bit(RSI, (bytes as i32).into());
+        return;
+    }
+
+    let in_range_label = instruction_stream.create_label();
+    instruction_stream.sub_Register64Bit_Immediate32Bit(RSI, (bytes as i32).into());
+    instruction_stream.jae_Label(in_range_label);
+    instruction_stream.add_Register64Bit_Immediate32Bit(RSI, (bytes as i32).into());
+    emit_failure(
+        instruction_stream,
+        target,
+        RuntimeErrorKind::PointerUnderflow,
+        site,
+    );
+    instruction_stream.attach_label(in_range_label);
+}
+
+// Make sure the byte at RSI + offset exists, `offset` may be negative.
+// Jump to rust function runtime_reserve, rust function will resize runtime memory if needed.
+fn emit_memory_reserve(
+    instruction_stream: &mut InstructionStream,
+    target: Target,
+    offset: isize,
+    site: u32,
+) {
+    if let Target::Elf(runtime) = target {
+        // the tape of an executable has a fixed size
+        runtime.emit_tape_check(instruction_stream, offset);
+        return;
+    }
+
+    let reserved_label = instruction_stream.create_label();
+    if offset >= 0 {
+        // skip the call when the byte is below RunContext.memory_end, the second field
+        instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RAX, RSI);
+        instruction_stream.add_Register64Bit_Immediate32Bit(RAX, (offset as i32).into());
+        instruction_stream.mov_Register64Bit_Any64BitMemory(
+            RCX,
+            MemoryOperand::base_64_displacement(RDX, 8i32.into()).into(),
+        );
+        instruction_stream.cmp_Register64Bit_Register64Bit_r64_rm64(RAX, RCX);
+        instruction_stream.jb_Label(reserved_label);
     }
+
+    // push stack: RSI, RDX
+    // here we don't really push RDI, because the head of brainfuck memory(vec part) may change after resize. Always use fn return value as RDI.
+    instruction_stream.push_Register64Bit_r64(RSI);
+    instruction_stream.push_Register64Bit_r64(RDX);
+    // in place of a third push, to call with the stack aligned to 16 bytes
+    instruction_stream.sub_Register64Bit_Immediate32Bit(RSP, 8i32.into());
+
+    // move context(RDX) to RDI(the first param of function runtime_reserve)
+    instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RDI, RDX);
+    instruction_stream.add_Register64Bit_Immediate32Bit(RSI, (offset as i32).into());
+
+    emit_context_call(instruction_stream, RUNTIME_RESERVE);
+
+    // here we don't really pop RDI. Always use fn return value as RDI.
+    instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RDI, RAX);
+    // keep the shift in RAX while restoring RDX
+    instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RAX, RDX);
+    instruction_stream.add_Register64Bit_Immediate32Bit(RSP, 8i32.into());
+
+    //pop stack: RDX, RSI
+    instruction_stream.pop_Register64Bit_r64(RDX);
+    instruction_stream.pop_Register64Bit_r64(RSI);
+    instruction_stream.add_Register64Bit_Register64Bit_r64_rm64(RSI, RAX);
+
+    // a null memory head means the memory could not grow
+    instruction_stream.mov_Register64Bit_Register64Bit_r64_rm64(RAX, RDI);
+    emit_call_check(instruction_stream, site);
+    instruction_stream.attach_label(reserved_label);
 }
 
-pub fn run<T: IBrainfuckRuntime>(jit_cache: &JITCache, runtime: &mut T) {
-    let new_index = unsafe {
-        let runtime_memory_vec_ptr = runtime.get_memory_vec_ptr();
-        let runtime_struct_ptr = transmute::<&mut T, *const u8>(runtime);
-        (jit_cache.function_pointer)(
-            runtime_memory_vec_ptr,
-            runtime.get_index() as u64,
-            runtime_struct_ptr,
-        )
+pub(crate) fn run(jit_cache: &JITCache, context: &mut RunContext) -> Result<(), RuntimeError> {
+    // the compiled code works on byte offsets, the tape keeps the cell index
+    let width = jit_cache.dialect.cell_width.bytes();
+    let exit = unsafe {
+        let memory_ptr = context.tape.memory.as_ptr();
+        context.memory_start = context.tape.memory_start() as u64;
+        context.memory_end = context.tape.memory_end() as u64;
+        context.runtime_functions = runtime_functions();
+        let offset = (context.tape.index * width) as u64;
+        let context_ptr = context as *mut RunContext as *mut u8;
+        (jit_cache.function_pointer)(memory_ptr, offset, context_ptr)
+    };
+    context.tape.index = exit.offset as usize / width;
+
+    let status = exit.status as u32;
+    let kind = match status & 0xff {
+        JIT_EXIT_COMPLETED => return Ok(()),
+        JIT_EXIT_OVERFLOW => RuntimeErrorKind::CellOverflow,
+        JIT_EXIT_UNDERFLOW => RuntimeErrorKind::PointerUnderflow,
+        _ => context.failure.take().unwrap(),
     };
+    Err(RuntimeError {
+        kind,
+        range: jit_cache.source_map.sites[(status >> 8) as usize],
+    })
+}
+
+pub fn interpret_jit(
+    input: &str,
+    dialect: Dialect,
+    mut reader: impl Read,
+    mut writer: impl Write,
+) -> Result<(), ExecutionError> {
+    let mut engine = JITEngine::new(dialect);
+    engine.prepare(input)?;
+    engine.run(&mut reader, &mut writer)?;
+    Ok(())
+}
+
+// Runtime functions return zero when they fail and put the reason into RunContext.failure.
 
-    runtime.set_index(new_index as usize);
+// Indices of the runtime functions in RunContext.runtime_functions, the field at 24. Compiled code
+// calls them through the context, so it holds no address of this process and another process can
+// load it, see code_cache.rs.
+const RUNTIME_INPUT: u32 = 0;
+const RUNTIME_FLUSH_OUTPUT: u32 = 1;
+const RUNTIME_REFILL_STEPS: u32 = 2;
+const RUNTIME_RESERVE: u32 = 3;
+
+fn runtime_functions() -> [usize; 4] {
+    [
+        runtime_input as *const () as usize,
+        runtime_flush_output as *const () as usize,
+        runtime_refill_steps as *const () as usize,
+        runtime_reserve as *const () as usize,
+    ]
 }
 
-pub fn interpret_jit(input: &str) {
-    let parse_result = parse(input).unwrap();
-    let mut memory = BrainfuckMemory::new();
-    let jit_cache = compile(&parse_result.parse_token_group);
-    run(&jit_cache, &mut memory);
+#[allow(unused_variables, dead_code)]
+unsafe extern "sysv64" fn runtime_input(context: &mut RunContext, offset: u64) -> u64 {
+    let index = offset as usize / context.tape.dialect.cell_width.bytes();
+    match context.input_cell(index) {
+        Ok(()) => 1,
+        Err(kind) => {
+            context.failure = Some(kind);
+            0
+        }
+    }
 }
 
 #[allow(unused_variables, dead_code)]
-unsafe extern "sysv64" fn input_char() -> u8 {
-    libc::getchar() as u8
+unsafe extern "sysv64" fn runtime_flush_output(context: &mut RunContext, offset: u64) -> u64 {
+    context.flush_output();
+    1
 }
 
 #[allow(unused_variables, dead_code)]
-unsafe extern "sysv64" fn output_char(c: u8) -> u8 {
-    libc::putchar(c as i32);
-    c
+unsafe extern "sysv64" fn runtime_refill_steps(context: &mut RunContext, offset: u64) -> u64 {
+    match context.refill_steps() {
+        Ok(()) => 1,
+        Err(kind) => {
+            context.failure = Some(kind);
+            0
+        }
+    }
 }
 
 #[allow(unused_variables, dead_code)]
-unsafe extern "sysv64" fn runtime_resize(
-    runtime: &mut BrainfuckMemory,
-    current_index: u8,
-) -> *const u8 {
-    if runtime.memory.len() - current_index as usize == 1 {
-        // may re-alloc new part of memory and copy the original data. should return memory head pointer
-        runtime.memory.resize(runtime.memory.len() * 2, 0);
+unsafe extern "sysv64" fn runtime_reserve(context: &mut RunContext, offset: u64) -> JITReserve {
+    // may re-alloc new part of memory and copy the original data. should return memory head pointer
+    match context.tape.reserve_byte(offset as i64 as isize) {
+        Ok(shift) => {
+            context.memory_start = context.tape.memory_start() as u64;
+            context.memory_end = context.tape.memory_end() as u64;
+            JITReserve {
+                memory: context.tape.memory.as_ptr(),
+                shift: shift as u64,
+            }
+        }
+        Err(kind) => {
+            context.failure = Some(kind);
+            JITReserve {
+                memory: std::ptr::null(),
+                shift: 0,
+            }
+        }
     }
+}
 
-    &runtime.memory[0] as *const u8
+#[cfg(test)]
+fn jit_engine(input: &str, dialect: Dialect) -> JITEngine {
+    let mut engine = JITEngine::new(dialect);
+    engine.prepare(input).unwrap();
+    engine
 }
 
 #[test]
-#[cfg(windows)]
+#[cfg(target_arch = "x86_64")]
 pub fn test_jit_simple() {
-    let input = ">>++<-";
-    let parse_result = parse(input).unwrap();
-
-    let mut memory = BrainfuckMemory::new();
-    let jit_cache = compile(&parse_result.parse_token_group);
-    run(&jit_cache, &mut memory);
-    assert_eq!(2, memory.memory[2]);
-    assert_eq!(u8::MAX, memory.memory[1]);
-    assert_eq!(1, memory.index);
+    let mut engine = jit_engine(">>++<-", Dialect::default());
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    assert_eq!(2, engine.tape.memory[2]);
+    assert_eq!(u8::MAX, engine.tape.memory[1]);
+    assert_eq!(1, engine.tape.index);
 }
 
 #[test]
-#[cfg(windows)]
+#[cfg(target_arch = "x86_64")]
 pub fn test_jit_with_io() {
-    let input = "+++++++++++++++++++++++++++++++++.";
-    let parse_result = parse(input).unwrap();
+    let mut output = Vec::new();
+    let mut engine = jit_engine("+++++++++++++++++++++++++++++++++.", Dialect::default());
+    engine.run(&mut std::io::empty(), &mut output).unwrap();
+    assert_eq!(b"!".to_vec(), output);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_with_io2() {
+    let dialect = Dialect {
+        eof: crate::EofBehaviour::Zero,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    let mut engine = jit_engine(",[+.,]-.", dialect);
+    engine.run(&mut "abc".as_bytes(), &mut output).unwrap();
+    assert_eq!(b"bcd\xff".to_vec(), output);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_with_loop() {
+    let mut engine = jit_engine("++[>+<-]", Dialect::default());
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    assert_eq!(2, engine.tape.memory[1]);
+    assert_eq!(0, engine.tape.memory[0]);
+    assert_eq!(0, engine.tape.index);
+}
 
-    let mut memory = BrainfuckMemory::new();
-    let jit_cache = compile(&parse_result.parse_token_group);
-    run(&jit_cache, &mut memory);
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_memory_extension() {
+    let mut engine = jit_engine(">>>>++", Dialect::default());
+    engine.tape.memory = vec![0; 3];
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
 
-    // should find "!" in test terminal
+    assert_eq!(6, engine.tape.memory.len());
+    assert_eq!(4, engine.tape.index);
+    assert_eq!(2, engine.tape.memory[4]);
 }
 
-// This test case need manual input, disable by default for auto testing
-// #[test]
-// pub fn test_jit_with_io2() {
-//     let input = ",+.";
-//     let parse_result = parse(input).unwrap();
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_long_moves() {
+    // one bounds check for the whole run of `>`, far past the allocated memory
+    let source = ">".repeat(3000) + "+" + &"<".repeat(2999);
+    let mut engine = jit_engine(&source, Dialect::default());
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    assert_eq!(1, engine.tape.index);
+    assert_eq!(1, engine.tape.cell(3000));
 
-//     let mut memory = BrainfuckMemory::new();
-//     let jit_cache = compile(&parse_result.parse_token_group);
-//     run(&jit_cache, &mut memory);
+    // the limit applies to the end of the run, also where memory is already allocated
+    engine.set_limits(Limits {
+        max_cells: Some(3000),
+        ..Default::default()
+    });
+    let error = engine.run(&mut std::io::empty(), &mut std::io::sink());
+    assert_eq!(
+        RuntimeErrorKind::MemoryLimitExceeded,
+        error.unwrap_err().kind
+    );
+    assert_eq!(1, engine.tape.index);
+}
 
-//     // manual input "A", should find a "B" as output
-// }
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_with_idioms() {
+    let mut engine = jit_engine("+++[->++>+<<]>[-]>[<]", Dialect::default());
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    assert_eq!(0, engine.tape.memory[0]);
+    assert_eq!(0, engine.tape.memory[1]);
+    assert_eq!(3, engine.tape.memory[2]);
+    assert_eq!(1, engine.tape.index);
+}
 
 #[test]
-#[cfg(windows)]
-pub fn test_jit_with_loop() {
-    let input = "++[>+<-]";
-    let parse_result = parse(input).unwrap();
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_mul_add_memory_extension() {
+    let mut engine = jit_engine("++[->>>>+++<<<<]", Dialect::default());
+    engine.tape.memory = vec![0; 3];
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
 
-    let mut memory = BrainfuckMemory::new();
-    let jit_cache = compile(&parse_result.parse_token_group);
-    run(&jit_cache, &mut memory);
-    assert_eq!(2, memory.memory[1]);
-    assert_eq!(0, memory.memory[0]);
-    assert_eq!(0, memory.index);
+    assert_eq!(6, engine.tape.memory.len());
+    assert_eq!(0, engine.tape.index);
+    assert_eq!(6, engine.tape.memory[4]);
 }
 
 #[test]
-#[cfg(windows)]
-pub fn test_jit_memory_extension() {
-    let input = ">>>>++";
-    let parse_result = parse(input).unwrap();
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_mul_add_large_factor() {
+    use crate::InterpreterEngine;
 
-    let mut memory = BrainfuckMemory::new();
-    memory.memory = vec![0; 3];
+    // a single multiplication, however large the factor
+    let small = jit_engine("+[->+++<]", Dialect::default());
+    let large = jit_engine(&format!("+[->{}<]", "+".repeat(1000)), Dialect::default());
+    assert_eq!(
+        small.jit_cache.as_ref().unwrap().code().len(),
+        large.jit_cache.as_ref().unwrap().code().len()
+    );
 
-    let jit_cache = compile(&parse_result.parse_token_group);
-    run(&jit_cache, &mut memory);
+    let sources = [
+        format!("+++[->{}>{}<<]", "+".repeat(1000), "-".repeat(300)),
+        format!("{}[->{}<]", "+".repeat(65), "+".repeat(1000)),
+        format!("{}[->{}<]", "+".repeat(66), "+".repeat(1000)),
+        format!(
+            "+++[->+>{}<<]>[-<+>]<[->>{}<<]",
+            "-".repeat(2),
+            "+".repeat(200)
+        ),
+    ];
+    for source in &sources {
+        for cell_width in [CellWidth::Bits8, CellWidth::Bits16, CellWidth::Bits32] {
+            for overflow in [OverflowBehaviour::Wrap, OverflowBehaviour::Error] {
+                let dialect = Dialect {
+                    cell_width,
+                    overflow,
+                    ..Default::default()
+                };
+                let mut interpreter = InterpreterEngine::new(dialect);
+                interpreter.prepare(source).unwrap();
+                let expected = interpreter.run(&mut std::io::empty(), &mut std::io::sink());
 
-    assert_eq!(6, memory.memory.len());
-    assert_eq!(4, memory.index);
-    assert_eq!(2, memory.memory[4]);
+                let mut engine = jit_engine(source, dialect);
+                let result = engine.run(&mut std::io::empty(), &mut std::io::sink());
+                assert_eq!(expected, result, "on {} with {:?}", source, dialect);
+                assert_eq!(
+                    interpreter.tape(),
+                    engine.tape(),
+                    "on {} with {:?}",
+                    source,
+                    dialect
+                );
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_with_wide_cells() {
+    let dialect = Dialect {
+        cell_width: CellWidth::Bits32,
+        ..Default::default()
+    };
+    let mut engine = jit_engine("-[->>+<<]>>+++<[<]", dialect);
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    assert_eq!(
+        vec![0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0],
+        engine.tape.memory[..12].to_vec()
+    );
+    assert_eq!(1, engine.tape.index);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_overflow_error() {
+    let dialect = Dialect {
+        overflow: OverflowBehaviour::Error,
+        ..Default::default()
+    };
+    let mut engine = jit_engine("+[->-<]", dialect);
+    let error = engine.run(&mut std::io::empty(), &mut std::io::sink());
+    assert_eq!(RuntimeErrorKind::CellOverflow, error.unwrap_err().kind);
+    assert_eq!(0, engine.tape.index);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_pointer_underflow() {
+    use brainfuck_analyzer::Position;
+
+    // the scan stops one cell left of the start
+    let mut engine = jit_engine("+>+\n[<]", Dialect::default());
+    let error = engine.run(&mut std::io::empty(), &mut std::io::sink());
+    let range = Range::new(Position::new(1, 0), Position::new(1, 3));
+    assert_eq!(
+        Err(RuntimeError {
+            kind: RuntimeErrorKind::PointerUnderflow,
+            range
+        }),
+        error
+    );
+    assert_eq!(0, engine.tape.index);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_step_limit() {
+    let mut engine = jit_engine(">+[>+<]", Dialect::default());
+    engine.set_limits(Limits {
+        max_steps: Some(1000),
+        ..Default::default()
+    });
+    let error = engine.run(&mut std::io::empty(), &mut std::io::sink());
+    assert_eq!(RuntimeErrorKind::StepLimitExceeded, error.unwrap_err().kind);
+    assert_eq!(1, engine.tape.index);
+    assert!(engine.tape.cell(2) > 200);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_bidirectional_tape() {
+    let dialect = Dialect {
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    let mut engine = jit_engine("+<<++[->>>+<<<]<+[<]", dialect);
+    engine.tape.memory = vec![0; 3];
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+
+    let tape = &engine.tape;
+    assert_eq!(-4, tape.position());
+    assert_eq!(2, tape.cell(tape.origin + 1));
+    assert_eq!(1, tape.cell(tape.origin));
+    assert_eq!(1, tape.cell(tape.origin - 3));
+}
+
+#[test]
+fn test_code_size_bound() {
+    let sources = [
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.",
+        ",[.,]>>>>>>>>>+[->+++<<<<<<-]<<<<<<<<[>]",
+        "-[--->+<]>[->>+++++++++++++<<]>>[<<<->>>-]+[>+<<]",
+    ];
+    let dialects = [
+        Dialect::default(),
+        Dialect {
+            cell_width: CellWidth::Bits32,
+            overflow: OverflowBehaviour::Error,
+            eof: crate::dialect::EofBehaviour::Error,
+            tape: TapeGrowth::Both,
+        },
+    ];
+    for source in sources {
+        for dialect in dialects {
+            let parse_result = parse(source).unwrap();
+            let instructions = dialect.instructions(&parse_result.parse_token_group);
+            let jit_cache = compile(&parse_result.parse_token_group, dialect);
+            assert!(
+                jit_cache.code().len() <= code_size_bound(&instructions) + MAX_SEQUENCE_BYTES,
+                "bound too small for {}",
+                source
+            );
+        }
+    }
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_large_program() {
+    // far more code than a page of memory holds
+    let source = ">+".repeat(2000) + "[<]";
+    let mut engine = jit_engine(&source, Dialect::default());
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    assert_eq!(0, engine.tape().index);
+    assert_eq!(1, engine.tape().cell(2000));
+}
+
+#[test]
+fn test_run_bounds() {
+    let bounds = |source: &str, width| {
+        let instructions =
+            Dialect::default().instructions(&parse(source).unwrap().parse_token_group);
+        run_bounds(&instructions, width)
+    };
+    assert_eq!(Some((-2, 3)), bounds("<<+>>>>>.<", 1));
+    assert_eq!(Some((0, 15)), bounds("[->>>+<<<]>+>", 4));
+    assert_eq!(Some((-4, 7)), bounds("[-<+>]>.<<", 4));
+    // a single check is emitted as before
+    assert_eq!(None, bounds(">+++.", 1));
+    assert_eq!(None, bounds(">[>]", 1));
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_run_bounds_fallback() {
+    use crate::InterpreterEngine;
+
+    // runs that leave the usable memory part way, the checked copy stops where the interpreter does
+    let sources = [
+        "+.>+.>>+.>+.<<<<<<<.",
+        ">>>+[<+.<+.<<+.<]",
+        "+.>.>.>.>.>.>.>.",
+    ];
+    for tape in [TapeGrowth::Right, TapeGrowth::Both] {
+        let dialect = Dialect {
+            tape,
+            ..Default::default()
+        };
+        for source in sources {
+            let limits = Limits {
+                max_cells: Some(5),
+                ..Default::default()
+            };
+            let mut expected_output = Vec::new();
+            let mut interpreter = InterpreterEngine::new(dialect);
+            interpreter.prepare(source).unwrap();
+            interpreter.set_limits(limits);
+            let expected = interpreter.run(&mut std::io::empty(), &mut expected_output);
+
+            let mut output = Vec::new();
+            let mut engine = jit_engine(source, dialect);
+            engine.set_limits(limits);
+            let result = engine.run(&mut std::io::empty(), &mut output);
+            assert_eq!(expected, result, "on {}", source);
+            assert_eq!(expected_output, output, "on {}", source);
+            assert_eq!(interpreter.tape().position(), engine.tape.position());
+        }
+    }
+}
+
+#[test]
+fn test_calls_keep_stack_aligned() {
+    use iced_x86::{Decoder, DecoderOptions, Mnemonic, Register};
+
+    // calls every runtime function, runtime_reserve from pointer moves and multiplications
+    let source = "+[>,.<-]>>>[-]<<<[->>>>>>+<<<<<<]<<[<]>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>.";
+    for tape in [TapeGrowth::Right, TapeGrowth::Both] {
+        let dialect = Dialect {
+            tape,
+            ..Default::default()
+        };
+        let jit_cache = compile(&parse(source).unwrap().parse_token_group, dialect);
+        // bytes on the stack since the caller's call, which pushed the return address
+        let mut pushed = 8;
+        let mut calls = 0;
+        for instruction in &mut Decoder::new(64, jit_cache.code(), DecoderOptions::NONE) {
+            let rsp = instruction.op0_register() == Register::RSP;
+            match instruction.mnemonic() {
+                Mnemonic::Push => pushed += 8,
+                Mnemonic::Pop => pushed -= 8,
+                Mnemonic::Sub if rsp => pushed += instruction.immediate(1),
+                Mnemonic::Add if rsp => pushed -= instruction.immediate(1),
+                Mnemonic::Call => {
+                    assert_eq!(0, pushed % 16, "call at {:#x}", instruction.ip());
+                    calls += 1;
+                }
+                Mnemonic::Ret => assert_eq!(8, pushed, "ret at {:#x}", instruction.ip()),
+                _ => {}
+            }
+        }
+        assert!(calls >= 4);
+    }
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_nested_loops() {
+    let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+    let mut output = Vec::new();
+    let mut engine = jit_engine(source, Dialect::default());
+    engine.run(&mut std::io::empty(), &mut output).unwrap();
+    assert_eq!(b"Hello World!\n".to_vec(), output);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_output_buffer_full() {
+    // 255 * 255 zero bytes without a newline, then the input is echoed
+    let mut output = Vec::new();
+    let mut engine = jit_engine("-[>-[>.<-]<-],.", Dialect::default());
+    engine.run(&mut "x".as_bytes(), &mut output).unwrap();
+    assert_eq!(255 * 255 + 1, output.len());
+    assert!(output[..255 * 255].iter().all(|byte| *byte == 0));
+    assert_eq!(b'x', output[255 * 255]);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_memory_growth_in_loops() {
+    // every round moves the counter two cells further, past the end of the memory
+    let mut engine = jit_engine("+++++[[->>+<<]>>-]", Dialect::default());
+    engine.tape.memory = vec![0; 3];
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    assert_eq!(10, engine.tape.index);
+    assert!(engine.tape.memory.len() > 10);
+
+    // the same to the left, with wide cells
+    let dialect = Dialect {
+        cell_width: CellWidth::Bits16,
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    let mut engine = jit_engine("+++++[[-<<+>>]<<-]+", dialect);
+    engine.tape.memory = vec![0; 6];
+    engine
+        .run(&mut std::io::empty(), &mut std::io::sink())
+        .unwrap();
+    assert_eq!(-10, engine.tape.position());
+    assert_eq!(1, engine.tape.current_cell());
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+pub fn test_jit_code_cache() {
+    let directory = std::env::temp_dir().join(format!("bfjit-engine-test-{}", std::process::id()));
+    let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.\n<<<<<<<";
+    for _ in 0..2 {
+        let mut engine = JITEngine::new(Dialect::default());
+        engine.set_code_cache(Some(CodeCache::new(&directory)));
+        engine.prepare(source).unwrap();
+        let mut output = Vec::new();
+        let error = engine.run(&mut std::io::empty(), &mut output).unwrap_err();
+        assert_eq!(b"Hello".to_vec(), output);
+        // the sites of errors are kept as well
+        assert_eq!(RuntimeErrorKind::PointerUnderflow, error.kind);
+        assert_eq!(1, error.range.start.line);
+    }
+    std::fs::remove_dir_all(&directory).unwrap();
 }
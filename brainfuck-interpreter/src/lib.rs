@@ -1,8 +1,34 @@
+mod asm_listing;
 mod autojit;
+mod c_emitter;
+mod code_cache;
 mod debug_interpreter;
+mod dialect;
+mod elf;
+mod engine;
+mod error;
 mod interpreter;
 mod jit;
+mod llvm_emitter;
+mod profiler;
+mod rust_emitter;
+mod tape;
+mod vm;
+mod wasm_emitter;
+pub use asm_listing::*;
 pub use autojit::*;
+pub use c_emitter::*;
+pub use code_cache::*;
 pub use debug_interpreter::*;
+pub use dialect::*;
+pub use elf::*;
+pub use engine::*;
+pub use error::*;
 pub use interpreter::*;
 pub use jit::*;
+pub use llvm_emitter::*;
+pub use profiler::*;
+pub use rust_emitter::*;
+pub use tape::*;
+pub use vm::*;
+pub use wasm_emitter::*;
@@ -0,0 +1,473 @@
+use std::fmt::Write;
+
+use crate::dialect::{Dialect, OverflowBehaviour, TapeGrowth};
+use crate::error::RuntimeErrorKind;
+use brainfuck_analyzer::{parse, Instruction, Op, ParseError, Range};
+
+// Errors a program can stop with, each gets a message and a block of `main` reporting it.
+const FAILURES: [RuntimeErrorKind; 4] = [
+    RuntimeErrorKind::PointerUnderflow,
+    RuntimeErrorKind::CellOverflow,
+    RuntimeErrorKind::EndOfInput,
+    RuntimeErrorKind::MemoryLimitExceeded,
+];
+
+/// Translates the program into textual LLVM IR, to be optimized and compiled by `opt` and `llc`.
+///
+/// `main` keeps the pointer in a stack slot and gives every loop a header and a body block, input
+/// and output go through `putchar` and `getchar`. The tape has room for `max_cells` cells on each
+/// side the dialect lets it grow to, as in the executables of `compile_elf`. Runtime errors write
+/// their message to file descriptor 2 and exit with status 1, so the module needs a POSIX libc.
+pub fn emit_llvm_ir(
+    source: &str,
+    dialect: Dialect,
+    max_cells: usize,
+) -> Result<String, ParseError> {
+    let parse_result = parse(source)?;
+    let instructions = dialect.instructions(&parse_result.parse_token_group);
+
+    let (tape_cells, origin) = tape_layout(dialect, max_cells).ok_or_else(|| ParseError {
+        range: Range::default(),
+        error_message: format!(
+            "A tape of {} cells on each side does not fit into memory.",
+            max_cells
+        ),
+    })?;
+    let cell = format!("i{}", dialect.cell_width.bytes() * 8);
+
+    let mut code = String::new();
+    writeln!(code, "; Generated by brainfuck-interpreter.").unwrap();
+    writeln!(code).unwrap();
+    writeln!(
+        code,
+        "@tape = internal global [{} x {}] zeroinitializer",
+        tape_cells, cell
+    )
+    .unwrap();
+    for (index, kind) in FAILURES.iter().enumerate() {
+        let message = format!("error: {}\n", kind);
+        writeln!(
+            code,
+            "@message.{} = private unnamed_addr constant [{} x i8] c\"{}\"",
+            index,
+            message.len(),
+            message.replace('\n', "\\0A")
+        )
+        .unwrap();
+    }
+    writeln!(code).unwrap();
+    writeln!(code, "declare i32 @putchar(i32)").unwrap();
+    writeln!(code, "declare i32 @getchar()").unwrap();
+    writeln!(code, "declare i32 @fflush(ptr)").unwrap();
+    writeln!(code, "declare i64 @write(i32, ptr, i64)").unwrap();
+    writeln!(code, "declare void @exit(i32) noreturn").unwrap();
+    writeln!(code).unwrap();
+    writeln!(
+        code,
+        "define internal void @fail(ptr %message, i64 %length) noreturn {{"
+    )
+    .unwrap();
+    writeln!(code, "  call i32 @fflush(ptr null)").unwrap();
+    writeln!(code, "  call i64 @write(i32 2, ptr %message, i64 %length)").unwrap();
+    writeln!(code, "  call void @exit(i32 1)").unwrap();
+    writeln!(code, "  unreachable").unwrap();
+    writeln!(code, "}}").unwrap();
+    writeln!(code).unwrap();
+
+    let mut generator = Generator {
+        code,
+        dialect,
+        tape: format!("[{} x {}]", tape_cells, cell),
+        cell,
+        tape_cells,
+        next_value: 0,
+        next_block: 0,
+        next_loop: 0,
+    };
+    writeln!(generator.code, "define i32 @main() {{").unwrap();
+    writeln!(generator.code, "entry:").unwrap();
+    generator.line("%p = alloca i64".to_string());
+    generator.line(format!("store i64 {}, ptr %p", origin));
+    generator.emit_instructions(&instructions);
+    generator.line("ret i32 0".to_string());
+    for (index, kind) in FAILURES.iter().enumerate() {
+        writeln!(generator.code).unwrap();
+        writeln!(generator.code, "{}:", failure_block(*kind)).unwrap();
+        generator.line(format!(
+            "call void @fail(ptr @message.{}, i64 {})",
+            index,
+            format!("error: {}\n", kind).len()
+        ));
+        generator.line("unreachable".to_string());
+    }
+    writeln!(generator.code, "}}").unwrap();
+    Ok(generator.code)
+}
+
+// Number of cells and index of the starting cell, `None` when the tape takes more bytes than the
+// i64 offsets into it can count.
+fn tape_layout(dialect: Dialect, max_cells: usize) -> Option<(usize, usize)> {
+    // the starting cell is always there
+    let cells = max_cells.max(1);
+    let (tape_cells, origin) = match dialect.tape {
+        TapeGrowth::Right => (cells, 0),
+        TapeGrowth::Both => (cells.checked_mul(2)?.checked_sub(1)?, cells - 1),
+    };
+    let tape_bytes = tape_cells.checked_mul(dialect.cell_width.bytes())?;
+    i64::try_from(tape_bytes).ok().map(|_| (tape_cells, origin))
+}
+
+// The block of `main` stopping with `kind`.
+fn failure_block(kind: RuntimeErrorKind) -> String {
+    let index = FAILURES
+        .iter()
+        .position(|failure| *failure == kind)
+        .unwrap();
+    format!("fail{}", index)
+}
+
+struct Generator {
+    code: String,
+    dialect: Dialect,
+    // the integer type of a cell and of the whole tape
+    cell: String,
+    tape: String,
+    tape_cells: usize,
+    next_value: usize,
+    next_block: usize,
+    next_loop: usize,
+}
+
+impl Generator {
+    fn line(&mut self, instruction: String) {
+        writeln!(self.code, "  {}", instruction).unwrap();
+    }
+
+    // A fresh name for a value.
+    fn value(&mut self) -> String {
+        self.next_value += 1;
+        format!("%v{}", self.next_value)
+    }
+
+    // A fresh name for a block.
+    fn block(&mut self) -> String {
+        self.next_block += 1;
+        format!("b{}", self.next_block)
+    }
+
+    fn start_block(&mut self, name: &str) {
+        writeln!(self.code, "{}:", name).unwrap();
+    }
+
+    fn emit_instructions(&mut self, instructions: &[Instruction]) {
+        for instruction in instructions {
+            match &instruction.op {
+                Op::Add(n) => {
+                    let index = self.pointer();
+                    self.emit_add(&index, &n.to_string());
+                }
+                Op::Move(n) => {
+                    let index = self.emit_offset(*n);
+                    self.line(format!("store i64 {}, ptr %p", index));
+                }
+                Op::Output => self.emit_output(),
+                Op::Input => self.emit_input(),
+                Op::Loop(body) => {
+                    let (header, end) = self.emit_loop_start();
+                    self.emit_instructions(body);
+                    self.line(format!("br label %{}", header));
+                    self.start_block(&end);
+                }
+                Op::SetZero => {
+                    let index = self.pointer();
+                    let address = self.address(&index);
+                    self.line(format!("store {} 0, ptr {}", self.cell, address));
+                }
+                Op::MulAdd(offset, factor) => self.emit_mul_add(*offset, *factor),
+                Op::ScanRight | Op::ScanLeft => {
+                    let step = if instruction.op == Op::ScanRight {
+                        1
+                    } else {
+                        -1
+                    };
+                    let (header, end) = self.emit_loop_start();
+                    let index = self.emit_offset(step);
+                    self.line(format!("store i64 {}, ptr %p", index));
+                    self.line(format!("br label %{}", header));
+                    self.start_block(&end);
+                }
+            }
+        }
+    }
+
+    // Branch into a loop header leaving for the returned end block once the current cell is zero,
+    // and start the body. The body has to branch back to the returned header.
+    fn emit_loop_start(&mut self) -> (String, String) {
+        let number = self.next_loop;
+        self.next_loop += 1;
+        let header = format!("loop{}.header", number);
+        let body = format!("loop{}.body", number);
+        let end = format!("loop{}.end", number);
+
+        self.line(format!("br label %{}", header));
+        self.start_block(&header);
+        let cell = self.load_current();
+        let nonzero = self.value();
+        self.line(format!("{} = icmp ne {} {}, 0", nonzero, self.cell, cell));
+        self.line(format!(
+            "br i1 {}, label %{}, label %{}",
+            nonzero, body, end
+        ));
+        self.start_block(&body);
+        (header, end)
+    }
+
+    // The index of the current cell.
+    fn pointer(&mut self) -> String {
+        let index = self.value();
+        self.line(format!("{} = load i64, ptr %p", index));
+        index
+    }
+
+    fn address(&mut self, index: &str) -> String {
+        let address = self.value();
+        self.line(format!(
+            "{} = getelementptr inbounds {}, ptr @tape, i64 0, i64 {}",
+            address, self.tape, index
+        ));
+        address
+    }
+
+    fn load_current(&mut self) -> String {
+        let index = self.pointer();
+        let address = self.address(&index);
+        let cell = self.value();
+        self.line(format!("{} = load {}, ptr {}", cell, self.cell, address));
+        cell
+    }
+
+    // The index of the cell `offset` away from the current one, stopping when it is off the tape.
+    // Left of the tape the index is negative, which is past its end when compared unsigned.
+    fn emit_offset(&mut self, offset: isize) -> String {
+        let kind = match (offset < 0, self.dialect.tape) {
+            (true, TapeGrowth::Right) => RuntimeErrorKind::PointerUnderflow,
+            _ => RuntimeErrorKind::MemoryLimitExceeded,
+        };
+        let pointer = self.pointer();
+        let index = self.value();
+        self.line(format!("{} = add i64 {}, {}", index, pointer, offset));
+        let inside = self.value();
+        self.line(format!(
+            "{} = icmp ult i64 {}, {}",
+            inside, index, self.tape_cells
+        ));
+        let next = self.block();
+        self.line(format!(
+            "br i1 {}, label %{}, label %{}",
+            inside,
+            next,
+            failure_block(kind)
+        ));
+        self.start_block(&next);
+        index
+    }
+
+    // Add the i64 `value`, a constant or a value name, to the cell at `index`.
+    fn emit_add(&mut self, index: &str, value: &str) {
+        let address = self.address(index);
+        let cell = self.value();
+        self.line(format!("{} = load {}, ptr {}", cell, self.cell, address));
+        let widened = self.widen(&cell);
+        let sum = self.value();
+        self.line(format!("{} = add i64 {}, {}", sum, widened, value));
+        if self.dialect.overflow == OverflowBehaviour::Error {
+            // results below zero are too large as unsigned numbers as well
+            let overflow = self.value();
+            self.line(format!(
+                "{} = icmp ugt i64 {}, {}",
+                overflow,
+                sum,
+                self.dialect.cell_width.max_value()
+            ));
+            let next = self.block();
+            self.line(format!(
+                "br i1 {}, label %{}, label %{}",
+                overflow,
+                failure_block(RuntimeErrorKind::CellOverflow),
+                next
+            ));
+            self.start_block(&next);
+        }
+        // truncating keeps the low bits, which wraps the value
+        let result = self.value();
+        self.line(format!("{} = trunc i64 {} to {}", result, sum, self.cell));
+        self.line(format!("store {} {}, ptr {}", self.cell, result, address));
+    }
+
+    fn emit_mul_add(&mut self, offset: isize, factor: i32) {
+        let cell = self.load_current();
+        let nonzero = self.value();
+        self.line(format!("{} = icmp ne {} {}, 0", nonzero, self.cell, cell));
+        let multiply = self.block();
+        let next = self.block();
+        self.line(format!(
+            "br i1 {}, label %{}, label %{}",
+            nonzero, multiply, next
+        ));
+        self.start_block(&multiply);
+        let target = self.emit_offset(offset);
+        let widened = self.widen(&cell);
+        let product = self.value();
+        self.line(format!("{} = mul i64 {}, {}", product, widened, factor));
+        self.emit_add(&target, &product);
+        self.line(format!("br label %{}", next));
+        self.start_block(&next);
+    }
+
+    fn emit_output(&mut self) {
+        let cell = self.load_current();
+        let byte = if self.cell == "i8" {
+            cell
+        } else {
+            let byte = self.value();
+            self.line(format!("{} = trunc {} {} to i8", byte, self.cell, cell));
+            byte
+        };
+        let character = self.value();
+        self.line(format!("{} = zext i8 {} to i32", character, byte));
+        self.line(format!("call i32 @putchar(i32 {})", character));
+    }
+
+    fn emit_input(&mut self) {
+        let character = self.value();
+        self.line(format!("{} = call i32 @getchar()", character));
+        let end_of_input = self.value();
+        self.line(format!("{} = icmp slt i32 {}, 0", end_of_input, character));
+        let read = self.block();
+        let end = self.block();
+        let next = self.block();
+        self.line(format!(
+            "br i1 {}, label %{}, label %{}",
+            end_of_input, end, read
+        ));
+
+        self.start_block(&read);
+        let index = self.pointer();
+        let address = self.address(&index);
+        let cell = if self.cell == "i32" {
+            character
+        } else {
+            let cell = self.value();
+            self.line(format!(
+                "{} = trunc i32 {} to {}",
+                cell, character, self.cell
+            ));
+            cell
+        };
+        self.line(format!("store {} {}, ptr {}", self.cell, cell, address));
+        self.line(format!("br label %{}", next));
+
+        self.start_block(&end);
+        match self.dialect.eof_value() {
+            Ok(None) => self.line(format!("br label %{}", next)),
+            Ok(Some(value)) => {
+                let index = self.pointer();
+                let address = self.address(&index);
+                self.line(format!("store {} {}, ptr {}", self.cell, value, address));
+                self.line(format!("br label %{}", next));
+            }
+            Err(kind) => self.line(format!("br label %{}", failure_block(kind))),
+        }
+        self.start_block(&next);
+    }
+
+    fn widen(&mut self, cell: &str) -> String {
+        let widened = self.value();
+        self.line(format!("{} = zext {} {} to i64", widened, self.cell, cell));
+        widened
+    }
+}
+
+#[test]
+fn test_emit_llvm_ir_blocks() {
+    let ir = emit_llvm_ir("+[>,[.,]<-]>>", Dialect::default(), 100).unwrap();
+    assert!(ir.contains("@tape = internal global [100 x i8] zeroinitializer"));
+    assert!(ir.contains("declare i32 @putchar(i32)"));
+    assert!(ir.contains("declare i32 @getchar()"));
+
+    let lines: Vec<&str> = ir.lines().collect();
+    let main = lines
+        .iter()
+        .position(|line| *line == "define i32 @main() {")
+        .unwrap();
+    let labels: Vec<&str> = lines[main..]
+        .iter()
+        .filter_map(|line| line.strip_suffix(':'))
+        .collect();
+    for number in 0..2 {
+        for part in ["header", "body", "end"] {
+            let label = format!("loop{}.{}", number, part);
+            assert!(labels.contains(&label.as_str()), "no block {}", label);
+        }
+    }
+    assert!(!labels.contains(&"loop2.header"));
+
+    // blocks end with a terminator and branches lead to blocks of main
+    for (index, line) in lines.iter().enumerate().skip(main + 2) {
+        if line.ends_with(':') {
+            let previous = lines[..index]
+                .iter()
+                .rev()
+                .find(|line| !line.is_empty())
+                .unwrap()
+                .trim();
+            assert!(
+                previous.starts_with("br ") || previous == "unreachable" || previous == "ret i32 0",
+                "block {} follows {}",
+                line,
+                previous
+            );
+        }
+        for target in line.split("label %").skip(1) {
+            let target = target.split(',').next().unwrap();
+            assert!(labels.contains(&target), "no block {}", target);
+        }
+    }
+}
+
+#[test]
+fn test_emit_llvm_ir_dialect() {
+    let dialect = Dialect {
+        cell_width: crate::dialect::CellWidth::Bits16,
+        overflow: OverflowBehaviour::Error,
+        eof: crate::dialect::EofBehaviour::MinusOne,
+        tape: TapeGrowth::Both,
+    };
+    let ir = emit_llvm_ir("+,<", dialect, 10).unwrap();
+    assert!(ir.contains("@tape = internal global [19 x i16] zeroinitializer"));
+    assert!(ir.contains("store i64 9, ptr %p"));
+    assert!(ir.contains("icmp ugt i64 %v5, 65535"));
+    assert!(ir.contains("store i16 65535"));
+    // left of a tape growing in both directions is the same error as right of it
+    assert!(ir.contains("label %fail3"));
+    assert!(!ir.contains("label %fail0"));
+
+    let ir = emit_llvm_ir("+,", Dialect::default(), 10).unwrap();
+    assert!(!ir.contains("icmp ugt"));
+    assert!(!ir.contains("store i8 255"));
+}
+
+#[test]
+fn test_llvm_tape_layout() {
+    let both = Dialect {
+        cell_width: crate::dialect::CellWidth::Bits16,
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    assert_eq!(Some((1, 0)), tape_layout(Dialect::default(), 0));
+    assert_eq!(Some((199, 99)), tape_layout(both, 100));
+    assert_eq!(None, tape_layout(both, usize::MAX));
+    assert_eq!(None, tape_layout(both, usize::MAX / 4 + 1));
+    let error = emit_llvm_ir("+", both, usize::MAX / 2).unwrap_err();
+    assert!(error.error_message.contains("does not fit"));
+}
@@ -1,40 +1,356 @@
-use crate::{autojit::interpret_auto_jit, jit::interpret_jit};
-use clap::Parser;
-use interpreter::interpret;
-use std::fs;
+use crate::{
+    asm_listing::emit_asm,
+    autojit::{AutoJITEngine, TieringPolicy},
+    c_emitter::emit_c,
+    code_cache::CodeCache,
+    elf::compile_elf,
+    jit::JITEngine,
+    llvm_emitter::emit_llvm_ir,
+    profiler::ProfilerEngine,
+    rust_emitter::emit_rust,
+    vm::VMEngine,
+    wasm_emitter::{emit_wasm, emit_wat},
+};
+use brainfuck_analyzer::Range;
+use clap::{Parser, Subcommand};
+use dialect::{CellWidth, Dialect, EofBehaviour, OverflowBehaviour, TapeGrowth};
+use engine::{Engine, Limits};
+use interpreter::InterpreterEngine;
+use std::{fs, io, path::Path, process, time::Duration};
 
+mod asm_listing;
 mod autojit;
+mod c_emitter;
+mod code_cache;
+mod dialect;
+mod elf;
+mod engine;
+mod error;
 mod interpreter;
 mod jit;
+mod llvm_emitter;
+mod profiler;
+mod rust_emitter;
+mod tape;
+mod vm;
+mod wasm_emitter;
 
 fn main() {
     let args = Args::parse();
+    match args.command {
+        Some(Command::Compile(compile_args)) => return compile(compile_args),
+        Some(Command::Transpile { language, args }) => return transpile(&language, args),
+        None => (),
+    }
+
+    let file = args.file.unwrap();
+    let contents = fs::read_to_string(&file).expect("Should have been able to read the file");
+    let dialect = args.dialect.dialect();
 
-    let contents = fs::read_to_string(args.file).expect("Should have been able to read the file");
+    if args.emit_asm {
+        match emit_asm(&contents, dialect) {
+            Ok(listing) => print!("{}", listing),
+            Err(error) => {
+                report_error(&file, &contents, &error.error_message, error.range);
+                process::exit(1);
+            }
+        }
+        return;
+    }
 
-    match args.mode.as_str() {
-        "interpret" => {
-            interpret(&contents);
+    let limits = Limits {
+        max_steps: args.max_steps,
+        max_cells: args.max_cells,
+        timeout: args.timeout.map(Duration::from_secs_f64),
+    };
+    if args.profile {
+        let mut engine = ProfilerEngine::new(dialect);
+        let succeeded = execute(&mut engine, limits, &file, &contents);
+        eprint!("{}", engine.profile());
+        let json = args.profile_json.unwrap_or_else(|| {
+            Path::new(&file)
+                .with_extension("profile.json")
+                .to_string_lossy()
+                .into_owned()
+        });
+        fs::write(json, engine.profile().to_json())
+            .expect("Should have been able to write the profile");
+        if !succeeded {
+            process::exit(1);
         }
+        return;
+    }
+
+    let succeeded = match args.mode.as_str() {
+        "interpret" => execute(
+            &mut InterpreterEngine::new(dialect),
+            limits,
+            &file,
+            &contents,
+        ),
+        "vm" => execute(&mut VMEngine::new(dialect), limits, &file, &contents),
         "jit" => {
-            interpret_jit(&contents);
+            let mut engine = JITEngine::new(dialect);
+            engine.set_code_cache(args.jit_cache.map(CodeCache::new));
+            execute(&mut engine, limits, &file, &contents)
         }
         "autojit" => {
-            interpret_auto_jit(&contents);
+            let mut engine = AutoJITEngine::new(dialect);
+            engine.set_tiering_policy(TieringPolicy {
+                threshold: args.jit_threshold,
+                max_queue: args.jit_queue,
+                promote_outer: !args.jit_no_promote,
+            });
+            let succeeded = execute(&mut engine, limits, &file, &contents);
+            if args.jit_report {
+                for compiled_loop in engine.compiled_loops() {
+                    eprintln!("{}", compiled_loop);
+                }
+            }
+            if args.jit_stats {
+                for loop_stats in engine.loop_stats() {
+                    eprintln!("{}", loop_stats);
+                }
+            }
+            if let Some(path) = &args.jit_stats_json {
+                fs::write(path, engine.stats_json())
+                    .expect("Should have been able to write the statistics");
+            }
+            succeeded
         }
         _ => panic!("Invalid mode value."),
+    };
+    if !succeeded {
+        process::exit(1);
+    }
+}
+
+// Run the program, reporting why it could not run or stopped. Returns whether it ran to the end.
+fn execute(engine: &mut dyn Engine, limits: Limits, file: &str, contents: &str) -> bool {
+    engine.set_limits(limits);
+    if let Err(error) = engine.prepare(contents) {
+        report_error(file, contents, &error.error_message, error.range);
+        return false;
+    }
+    if let Err(error) = engine.run(&mut io::stdin(), &mut io::stdout()) {
+        report_error(file, contents, &error.to_string(), error.range);
+        return false;
+    }
+    true
+}
+
+// Write the program as a standalone executable, next to the source unless an output is given.
+fn compile(args: CompileArgs) {
+    let contents = fs::read_to_string(&args.file).expect("Should have been able to read the file");
+    let executable = match compile_elf(&contents, args.dialect.dialect(), args.max_cells) {
+        Ok(executable) => executable,
+        Err(error) => {
+            report_error(&args.file, &contents, &error.error_message, error.range);
+            process::exit(1);
+        }
+    };
+
+    let output = args.output_path("");
+    fs::write(&output, executable).expect("Should have been able to write the executable");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&output, fs::Permissions::from_mode(0o755))
+            .expect("Should have been able to make the executable runnable");
     }
 }
 
+// Write the program as source code of another language.
+fn transpile(language: &str, args: CompileArgs) {
+    let contents = fs::read_to_string(&args.file).expect("Should have been able to read the file");
+    let dialect = args.dialect.dialect();
+    let result = match language {
+        "c" => emit_c(&contents, dialect, args.max_cells).map(String::into_bytes),
+        "ll" => emit_llvm_ir(&contents, dialect, args.max_cells).map(String::into_bytes),
+        "rs" => emit_rust(&contents, dialect, Some(args.max_cells)).map(String::into_bytes),
+        "wat" => emit_wat(&contents, dialect, args.max_cells).map(String::into_bytes),
+        "wasm" => emit_wasm(&contents, dialect, args.max_cells),
+        _ => panic!("Invalid language value."),
+    };
+    match result {
+        Ok(code) => fs::write(args.output_path(language), code)
+            .expect("Should have been able to write the output"),
+        Err(error) => {
+            report_error(&args.file, &contents, &error.error_message, error.range);
+            process::exit(1);
+        }
+    }
+}
+
+// Print the message followed by the source line of `range`, with carets under the range.
+fn report_error(file: &str, contents: &str, message: &str, range: Range) {
+    let line_number = range.start.line as usize;
+    let line = contents.lines().nth(line_number).unwrap_or("");
+    let start = range.start.character as usize;
+    let end = if range.end.line == range.start.line {
+        (range.end.character as usize).max(start + 1)
+    } else {
+        line.chars().count().max(start + 1)
+    };
+    let gutter = " ".repeat((line_number + 1).to_string().len());
+
+    eprintln!("error: {}", message);
+    eprintln!("{}--> {}:{}:{}", gutter, file, line_number + 1, start + 1);
+    eprintln!("{} |", gutter);
+    eprintln!("{} | {}", line_number + 1, line);
+    eprintln!(
+        "{} | {}{}",
+        gutter,
+        " ".repeat(start),
+        "^".repeat(end - start)
+    );
+}
+
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    // Valid value = interprete / jit / autojit. Default value = interprete.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Valid value = interprete / vm / jit / autojit. Default value = interprete.
     #[arg(short, long, default_value_t = String::from("interpret"))]
     mode: String,
 
+    // brainfuck file path
+    #[arg(short, long, required = true)]
+    file: Option<String>,
+
+    #[command(flatten)]
+    dialect: DialectArgs,
+
+    // Stop after this many executed instructions and loop checks. Unlimited by default.
+    #[arg(long)]
+    max_steps: Option<u64>,
+
+    // Stop when the pointer moves this many cells away from the start. Unlimited by default.
+    #[arg(long)]
+    max_cells: Option<usize>,
+
+    // Stop after this many seconds. Unlimited by default.
+    #[arg(long)]
+    timeout: Option<f64>,
+
+    // Directory to keep programs compiled in jit mode in, later runs of the same program load them.
+    // Cached code runs unchecked, the directory must not be writable by other users.
+    #[arg(long)]
+    jit_cache: Option<String>,
+
+    // Iterations a loop is interpreted in autojit mode before it is compiled.
+    #[arg(long, default_value_t = 3)]
+    jit_threshold: usize,
+
+    // Loops waiting for the compiler at most in autojit mode.
+    #[arg(long, default_value_t = 4)]
+    jit_queue: usize,
+
+    // Compile only the hot loops in autojit mode, not the loops around them.
+    #[arg(long)]
+    jit_no_promote: bool,
+
+    // Print which loops autojit mode compiled, and when, to stderr after the run.
+    #[arg(long)]
+    jit_report: bool,
+
+    // Print how often every loop ran interpreted and compiled in autojit mode to stderr after the run.
+    #[arg(long)]
+    jit_stats: bool,
+
+    // Write the statistics of autojit mode and the loops it compiled to this file as JSON.
+    #[arg(long)]
+    jit_stats_json: Option<String>,
+
+    // Run the program counting every command and loop, instead of in the given mode. Prints the
+    // counts to stderr and writes them as JSON next to the file.
+    #[arg(long)]
+    profile: bool,
+
+    // Where --profile writes the JSON. Default value = the file path with extension profile.json.
+    #[arg(long)]
+    profile_json: Option<String>,
+
+    // Print the x86-64 code the JIT generates for the program instead of running it.
+    #[arg(long)]
+    emit_asm: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compile the program into a standalone x86-64 Linux executable
+    Compile(CompileArgs),
+    /// Translate the program into source code of another language
+    Transpile {
+        // Valid value = c | ll | rs | wat | wasm.
+        #[arg(short, long, default_value_t = String::from("c"))]
+        language: String,
+
+        #[command(flatten)]
+        args: CompileArgs,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct CompileArgs {
     // brainfuck file path
     #[arg(short, long)]
     file: String,
+
+    // Path of the output. Default value = the file path with the extension of the output.
+    #[arg(short, long)]
+    output: Option<String>,
+
+    #[command(flatten)]
+    dialect: DialectArgs,
+
+    // Cells the output has room for on each side the tape grows to.
+    #[arg(long, default_value_t = 1 << 20)]
+    max_cells: usize,
+}
+
+impl CompileArgs {
+    fn output_path(&self, extension: &str) -> String {
+        match &self.output {
+            Some(output) => output.clone(),
+            None => Path::new(&self.file)
+                .with_extension(extension)
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct DialectArgs {
+    // Bits of one memory cell. Valid value = 8 / 16 / 32.
+    #[arg(long, default_value = "8")]
+    cell_width: CellWidth,
+
+    // Valid value = wrap / error.
+    #[arg(long, default_value = "wrap")]
+    overflow: OverflowBehaviour,
+
+    // Value stored by `,` at end of input. Valid value = unchanged / zero / minus-one / error.
+    #[arg(long, default_value = "unchanged")]
+    eof: EofBehaviour,
+
+    // Whether the pointer may move left of the starting cell. Valid value = right / both.
+    #[arg(long, default_value = "right")]
+    tape: TapeGrowth,
+}
+
+impl DialectArgs {
+    fn dialect(&self) -> Dialect {
+        Dialect {
+            cell_width: self.cell_width,
+            overflow: self.overflow,
+            eof: self.eof,
+            tape: self.tape,
+        }
+    }
 }
@@ -0,0 +1,399 @@
+use std::{
+    cmp::Reverse,
+    fmt,
+    io::{Read, Write},
+    time::{Duration, Instant},
+};
+
+use crate::dialect::Dialect;
+use crate::engine::{Engine, Limits, RunContext};
+use crate::error::RuntimeError;
+use crate::tape::Tape;
+use brainfuck_analyzer::{parse, ParseError, Range, TokenGroup, TokenType};
+use serde_json::json;
+
+/// How often one command of the source ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenProfile {
+    pub range: Range,
+    /// The command, one of `+-<>.,`.
+    pub command: char,
+    pub count: u64,
+}
+
+/// How often one loop, a `SubGroup` of the source, ran and the time spent in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopProfile {
+    /// Source of the loop, brackets included.
+    pub range: Range,
+    /// Times the program reached the loop.
+    pub entries: u64,
+    pub iterations: u64,
+    /// Time from reaching the loop to leaving it, loops inside it included.
+    pub time: Duration,
+}
+
+/// What the profiler engine counted during a run, commands and loops in source order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub tokens: Vec<TokenProfile>,
+    pub loops: Vec<LoopProfile>,
+    /// Commands run and loop conditions checked.
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+impl Profile {
+    /// The profile as JSON, positions count from zero like in the language server.
+    pub fn to_json(&self) -> String {
+        let range = |range: Range| {
+            json!({
+                "start": { "line": range.start.line, "character": range.start.character },
+                "end": { "line": range.end.line, "character": range.end.character },
+            })
+        };
+        let tokens: Vec<_> = self
+            .tokens
+            .iter()
+            .map(|token| {
+                json!({
+                    "range": range(token.range),
+                    "command": token.command.to_string(),
+                    "count": token.count,
+                })
+            })
+            .collect();
+        let loops: Vec<_> = self
+            .loops
+            .iter()
+            .map(|profile| {
+                json!({
+                    "range": range(profile.range),
+                    "entries": profile.entries,
+                    "iterations": profile.iterations,
+                    "seconds": profile.time.as_secs_f64(),
+                })
+            })
+            .collect();
+        serde_json::to_string_pretty(&json!({
+            "instructions": self.instructions,
+            "seconds": self.time.as_secs_f64(),
+            "tokens": tokens,
+            "loops": loops,
+        }))
+        .unwrap()
+    }
+}
+
+// The summary, then every loop from the slowest, then the counts of the commands. Commands next to
+// each other that ran equally often share a line of the report.
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} instructions in {:?}", self.instructions, self.time)?;
+        let mut loops: Vec<&LoopProfile> = self.loops.iter().collect();
+        loops.sort_by_key(|profile| Reverse(profile.time));
+        for profile in loops {
+            writeln!(
+                f,
+                "loop at {}:{}: {} entries, {} iterations, {:?}",
+                profile.range.start.line + 1,
+                profile.range.start.character + 1,
+                profile.entries,
+                profile.iterations,
+                profile.time
+            )?;
+        }
+        let mut tokens = self.tokens.iter().peekable();
+        while let Some(first) = tokens.next() {
+            let mut commands = first.command.to_string();
+            let mut end = first.range.end;
+            while let Some(next) =
+                tokens.next_if(|next| next.count == first.count && next.range.start == end)
+            {
+                commands.push(next.command);
+                end = next.range.end;
+            }
+            writeln!(
+                f,
+                "{}:{}-{} {} {}",
+                first.range.start.line + 1,
+                first.range.start.character + 1,
+                end.character,
+                commands,
+                first.count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the program command by command, without folding or idioms, and counts how often every
+/// command and loop ran, see `Profile`. Every command and loop condition is a step of `Limits`.
+pub struct ProfilerEngine {
+    program: Vec<Node>,
+    profile: Profile,
+    tape: Tape,
+    limits: Limits,
+}
+
+// The token tree with comments left out. Commands and loops hold their index in the profile.
+enum Node {
+    Command(TokenType, usize),
+    Loop(Vec<Node>, usize),
+}
+
+impl ProfilerEngine {
+    pub fn new(dialect: Dialect) -> ProfilerEngine {
+        ProfilerEngine {
+            program: Vec::new(),
+            profile: Profile::default(),
+            tape: Tape::new(dialect),
+            limits: Limits::default(),
+        }
+    }
+
+    /// The counts of the last run, also when it stopped with an error.
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+}
+
+impl Engine for ProfilerEngine {
+    fn prepare(&mut self, source: &str) -> Result<(), ParseError> {
+        let parse_result = parse(source)?;
+        self.profile = Profile::default();
+        self.program = nodes(&parse_result.parse_token_group, &mut self.profile);
+        self.tape = Tape::new(self.tape.dialect);
+        Ok(())
+    }
+
+    fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), RuntimeError> {
+        let profile = &mut self.profile;
+        profile.tokens.iter_mut().for_each(|token| token.count = 0);
+        for loop_profile in &mut profile.loops {
+            loop_profile.entries = 0;
+            loop_profile.iterations = 0;
+            loop_profile.time = Duration::ZERO;
+        }
+
+        let mut context = RunContext::new(&mut self.tape, input, output, &self.limits);
+        let start = Instant::now();
+        let result = execute(&mut context, &self.program, profile);
+        context.flush_output();
+        profile.time = start.elapsed();
+        profile.instructions = profile.tokens.iter().map(|token| token.count).sum::<u64>()
+            + profile
+                .loops
+                .iter()
+                .map(|loop_profile| loop_profile.entries + loop_profile.iterations)
+                .sum::<u64>();
+        result
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    fn tape_mut(&mut self) -> &mut Tape {
+        &mut self.tape
+    }
+}
+
+// Number the commands and loops of `token_group` in source order, adding them to the profile.
+fn nodes(token_group: &TokenGroup, profile: &mut Profile) -> Vec<Node> {
+    let mut result = Vec::new();
+    for token in token_group.tokens() {
+        let command = match &token.token_type {
+            TokenType::Increment => '+',
+            TokenType::Decrement => '-',
+            TokenType::PointerIncrement => '>',
+            TokenType::PointerDecrement => '<',
+            TokenType::Output => '.',
+            TokenType::Input => ',',
+            TokenType::SubGroup(sub_group) => {
+                let index = profile.loops.len();
+                profile.loops.push(LoopProfile {
+                    range: token.range,
+                    entries: 0,
+                    iterations: 0,
+                    time: Duration::ZERO,
+                });
+                result.push(Node::Loop(nodes(sub_group, profile), index));
+                continue;
+            }
+            _ => continue,
+        };
+        result.push(Node::Command(
+            token.token_type.clone(),
+            profile.tokens.len(),
+        ));
+        profile.tokens.push(TokenProfile {
+            range: token.range,
+            command,
+            count: 0,
+        });
+    }
+    result
+}
+
+fn execute(
+    context: &mut RunContext,
+    nodes: &[Node],
+    profile: &mut Profile,
+) -> Result<(), RuntimeError> {
+    for node in nodes {
+        match node {
+            Node::Command(token_type, index) => {
+                let token = &mut profile.tokens[*index];
+                let range = token.range;
+                let error = |kind| RuntimeError { kind, range };
+                context.step(1).map_err(error)?;
+                token.count += 1;
+                let tape = &mut *context.tape;
+                match token_type {
+                    TokenType::Increment => tape.add_cell(tape.index, 1).map_err(error)?,
+                    TokenType::Decrement => tape.add_cell(tape.index, -1).map_err(error)?,
+                    TokenType::PointerIncrement => {
+                        tape.index = tape.offset_index(1).map_err(error)?
+                    }
+                    TokenType::PointerDecrement => {
+                        tape.index = tape.offset_index(-1).map_err(error)?
+                    }
+                    TokenType::Output => context.output_cell(context.tape.index),
+                    TokenType::Input => context.input_cell(context.tape.index).map_err(error)?,
+                    _ => unreachable!(),
+                }
+            }
+            Node::Loop(body, index) => {
+                let start = Instant::now();
+                let result = execute_loop(context, body, *index, profile);
+                profile.loops[*index].time += start.elapsed();
+                result?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn execute_loop(
+    context: &mut RunContext,
+    body: &[Node],
+    index: usize,
+    profile: &mut Profile,
+) -> Result<(), RuntimeError> {
+    let range = profile.loops[index].range;
+    let error = |kind| RuntimeError { kind, range };
+    context.step(1).map_err(error)?;
+    profile.loops[index].entries += 1;
+    while context.tape.current_cell() != 0 {
+        execute(context, body, profile)?;
+        profile.loops[index].iterations += 1;
+        context.step(1).map_err(error)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn run_profiler(source: &str, input: &str) -> (ProfilerEngine, Result<(), RuntimeError>, Vec<u8>) {
+    let mut engine = ProfilerEngine::new(Dialect::default());
+    engine.prepare(source).unwrap();
+    let mut output = Vec::new();
+    let result = engine.run(&mut input.as_bytes(), &mut output);
+    (engine, result, output)
+}
+
+#[test]
+fn test_profiler_counts() {
+    use brainfuck_analyzer::Position;
+
+    let (engine, result, output) = run_profiler("+++[>++[-]<-]\n>,.", "a");
+    assert_eq!(Ok(()), result);
+    assert_eq!(b"a".to_vec(), output);
+    let profile = engine.profile();
+
+    let counts: Vec<(char, u64)> = profile
+        .tokens
+        .iter()
+        .map(|token| (token.command, token.count))
+        .collect();
+    assert_eq!(
+        vec![
+            ('+', 1),
+            ('+', 1),
+            ('+', 1),
+            ('>', 3),
+            ('+', 3),
+            ('+', 3),
+            ('-', 6),
+            ('<', 3),
+            ('-', 3),
+            ('>', 1),
+            (',', 1),
+            ('.', 1),
+        ],
+        counts
+    );
+    assert_eq!(Position::new(1, 2), profile.tokens[11].range.start);
+
+    assert_eq!(2, profile.loops.len());
+    assert_eq!(Position::new(0, 3), profile.loops[0].range.start);
+    assert_eq!(Position::new(0, 13), profile.loops[0].range.end);
+    assert_eq!(
+        (1, 3),
+        (profile.loops[0].entries, profile.loops[0].iterations)
+    );
+    assert_eq!(
+        (3, 6),
+        (profile.loops[1].entries, profile.loops[1].iterations)
+    );
+    assert!(profile.loops[0].time >= profile.loops[1].time);
+    assert_eq!(27 + 4 + 9, profile.instructions);
+
+    let report = profile.to_string();
+    assert!(report.starts_with("40 instructions in "));
+    assert!(report.contains("loop at 1:4: 1 entries, 3 iterations"));
+    assert!(report.contains("\n1:1-3 +++ 1\n1:5-7 >++ 3\n1:9-9 - 6\n"));
+    assert!(report.ends_with("\n2:1-3 >,. 1\n"));
+
+    let json: serde_json::Value = serde_json::from_str(&profile.to_json()).unwrap();
+    assert_eq!(40, json["instructions"]);
+    assert_eq!(6, json["tokens"][6]["count"]);
+    assert_eq!("-", json["tokens"][6]["command"]);
+    assert_eq!(8, json["tokens"][6]["range"]["start"]["character"]);
+    assert_eq!(3, json["loops"][1]["entries"]);
+}
+
+#[test]
+fn test_profiler_keeps_counts_of_failed_runs() {
+    use crate::error::RuntimeErrorKind;
+    use brainfuck_analyzer::Position;
+
+    // the failing command counts as run
+    let (engine, result, _) = run_profiler("+\n>><<<", "");
+    let error = result.unwrap_err();
+    assert_eq!(RuntimeErrorKind::PointerUnderflow, error.kind);
+    assert_eq!(Position::new(1, 4), error.range.start);
+    assert!(engine.profile().tokens.iter().all(|token| token.count == 1));
+    assert_eq!(6, engine.profile().instructions);
+
+    // every command and condition is a step: `+`, entering the loop, then `+` and the check in turns
+    let mut engine = ProfilerEngine::new(Dialect::default());
+    engine.prepare("+[+]").unwrap();
+    engine.set_limits(Limits {
+        max_steps: Some(100),
+        ..Default::default()
+    });
+    let error = engine.run(&mut std::io::empty(), &mut std::io::sink());
+    assert_eq!(RuntimeErrorKind::StepLimitExceeded, error.unwrap_err().kind);
+    let profile = engine.profile();
+    assert_eq!(100, profile.instructions);
+    assert_eq!(49, profile.tokens[1].count);
+    assert_eq!(
+        (1, 49),
+        (profile.loops[0].entries, profile.loops[0].iterations)
+    );
+}
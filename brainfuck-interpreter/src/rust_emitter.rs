@@ -0,0 +1,402 @@
+use std::fmt::Write;
+
+use crate::dialect::{CellWidth, Dialect, OverflowBehaviour, TapeGrowth};
+use crate::error::RuntimeErrorKind;
+use brainfuck_analyzer::{parse, Instruction, Op, ParseError};
+
+/// Translates the program into a Rust module exposing
+/// `pub fn run(input: &mut impl Read, output: &mut impl Write) -> io::Result<()>`, which fails
+/// with the error writing the output or with the message of the runtime error that stopped the program.
+///
+/// The module only depends on `std`, its tape grows like `Tape` does and stops with
+/// `MemoryLimitExceeded` once the pointer moves `max_cells` cells away from the start. A build script
+/// can write it to `OUT_DIR` and the crate pull it in with `include!`.
+pub fn emit_rust(
+    source: &str,
+    dialect: Dialect,
+    max_cells: Option<usize>,
+) -> Result<String, ParseError> {
+    let parse_result = parse(source)?;
+    let instructions = dialect.instructions(&parse_result.parse_token_group);
+
+    let cell_type = match dialect.cell_width {
+        CellWidth::Bits8 => "u8",
+        CellWidth::Bits16 => "u16",
+        CellWidth::Bits32 => "u32",
+    };
+
+    let mut code = String::new();
+    writeln!(code, "// Generated by brainfuck-interpreter.").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "use std::io::{{self, Read, Write}};").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "type Cell = {};", cell_type).unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "struct Tape {{").unwrap();
+    writeln!(code, "    cells: Vec<Cell>,").unwrap();
+    writeln!(code, "    index: usize,").unwrap();
+    writeln!(code, "    origin: usize,").unwrap();
+    writeln!(code, "}}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "// Not every program needs every method.").unwrap();
+    writeln!(code, "#[allow(dead_code)]").unwrap();
+    writeln!(code, "impl Tape {{").unwrap();
+    emit_offset_method(&mut code, dialect, max_cells);
+    writeln!(code).unwrap();
+    emit_add_method(&mut code, dialect);
+    writeln!(code).unwrap();
+    writeln!(
+        code,
+        "    fn input(&mut self, input: &mut impl Read) -> io::Result<()> {{"
+    )
+    .unwrap();
+    writeln!(code, "        let mut byte = [0u8];").unwrap();
+    writeln!(code, "        match input.read(&mut byte) {{").unwrap();
+    match dialect.eof_value() {
+        Ok(None) => writeln!(code, "            Ok(0) => (),").unwrap(),
+        Ok(Some(value)) => writeln!(
+            code,
+            "            Ok(0) => self.cells[self.index] = {},",
+            value
+        )
+        .unwrap(),
+        Err(kind) => writeln!(
+            code,
+            "            Ok(0) => return Err(io::Error::other(\"{}\")),",
+            kind
+        )
+        .unwrap(),
+    }
+    writeln!(
+        code,
+        "            Ok(_) => self.cells[self.index] = Cell::from(byte[0]),"
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "            Err(_) => return Err(io::Error::other(\"{}\")),",
+        RuntimeErrorKind::EndOfInput
+    )
+    .unwrap();
+    writeln!(code, "        }}").unwrap();
+    writeln!(code, "        Ok(())").unwrap();
+    writeln!(code, "    }}").unwrap();
+    writeln!(code).unwrap();
+    writeln!(
+        code,
+        "    fn output(&self, output: &mut impl Write) -> io::Result<()> {{"
+    )
+    .unwrap();
+    // the low byte of the cell
+    let byte = match dialect.cell_width {
+        CellWidth::Bits8 => "self.cells[self.index]",
+        _ => "self.cells[self.index] as u8",
+    };
+    writeln!(code, "        output.write_all(&[{}])", byte).unwrap();
+    writeln!(code, "    }}").unwrap();
+    writeln!(code, "}}").unwrap();
+    writeln!(code).unwrap();
+
+    // unused parameters are named `_` to keep the generated code free of warnings
+    let input = if uses(&instructions, &Op::Input) {
+        "input"
+    } else {
+        "_input"
+    };
+    let output = if uses(&instructions, &Op::Output) {
+        "output"
+    } else {
+        "_output"
+    };
+    writeln!(
+        code,
+        "/// Runs the program, failing with the message of the runtime error that stopped it."
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "pub fn run({}: &mut impl Read, {}: &mut impl Write) -> io::Result<()> {{",
+        input, output
+    )
+    .unwrap();
+    // a program without commands leaves the tape unused, one only printing leaves it unchanged
+    writeln!(code, "    #[allow(unused)]").unwrap();
+    writeln!(code, "    let mut tape = Tape {{").unwrap();
+    writeln!(code, "        cells: vec![0; 1000],").unwrap();
+    writeln!(code, "        index: 0,").unwrap();
+    writeln!(code, "        origin: 0,").unwrap();
+    writeln!(code, "    }};").unwrap();
+    emit_instructions(&mut code, &instructions, 1);
+    writeln!(code, "    Ok(())").unwrap();
+    writeln!(code, "}}").unwrap();
+    Ok(code)
+}
+
+fn emit_offset_method(code: &mut String, dialect: Dialect, max_cells: Option<usize>) {
+    writeln!(
+        code,
+        "    // Index of the cell `offset` away from the current one, growing the tape when it is beyond"
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "    // either end. Growing on the left moves every cell, `index` included."
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "    fn at(&mut self, offset: isize) -> io::Result<usize> {{"
+    )
+    .unwrap();
+    writeln!(code, "        let target = self.index as isize + offset;").unwrap();
+    if dialect.tape == TapeGrowth::Right {
+        writeln!(code, "        if target < 0 {{").unwrap();
+        writeln!(
+            code,
+            "            return Err(io::Error::other(\"{}\"));",
+            RuntimeErrorKind::PointerUnderflow
+        )
+        .unwrap();
+        writeln!(code, "        }}").unwrap();
+    }
+    if let Some(max_cells) = max_cells {
+        writeln!(
+            code,
+            "        if (target - self.origin as isize).unsigned_abs() >= {} {{",
+            max_cells
+        )
+        .unwrap();
+        writeln!(
+            code,
+            "            return Err(io::Error::other(\"{}\"));",
+            RuntimeErrorKind::MemoryLimitExceeded
+        )
+        .unwrap();
+        writeln!(code, "        }}").unwrap();
+    }
+    if dialect.tape == TapeGrowth::Both {
+        writeln!(code, "        if target < 0 {{").unwrap();
+        writeln!(
+            code,
+            "            let added = target.unsigned_abs().max(self.cells.len());"
+        )
+        .unwrap();
+        writeln!(
+            code,
+            "            self.cells.resize(self.cells.len() + added, 0);"
+        )
+        .unwrap();
+        writeln!(code, "            self.cells.rotate_right(added);").unwrap();
+        writeln!(code, "            self.index += added;").unwrap();
+        writeln!(code, "            self.origin += added;").unwrap();
+        writeln!(
+            code,
+            "            return Ok((target + added as isize) as usize);"
+        )
+        .unwrap();
+        writeln!(code, "        }}").unwrap();
+    }
+    writeln!(code, "        let target = target as usize;").unwrap();
+    writeln!(code, "        if target >= self.cells.len() {{").unwrap();
+    writeln!(
+        code,
+        "            self.cells.resize((target + 1).max(self.cells.len() * 2), 0);"
+    )
+    .unwrap();
+    writeln!(code, "        }}").unwrap();
+    writeln!(code, "        Ok(target)").unwrap();
+    writeln!(code, "    }}").unwrap();
+}
+
+fn emit_add_method(code: &mut String, dialect: Dialect) {
+    writeln!(
+        code,
+        "    fn add(&mut self, index: usize, value: i64) -> io::Result<()> {{"
+    )
+    .unwrap();
+    match dialect.overflow {
+        OverflowBehaviour::Wrap => {
+            writeln!(
+                code,
+                "        self.cells[index] = i64::from(self.cells[index]).wrapping_add(value) as Cell;"
+            )
+            .unwrap();
+        }
+        OverflowBehaviour::Error => {
+            writeln!(
+                code,
+                "        let result = i64::from(self.cells[index]) + value;"
+            )
+            .unwrap();
+            writeln!(
+                code,
+                "        self.cells[index] = Cell::try_from(result).map_err(|_| io::Error::other(\"{}\"))?;",
+                RuntimeErrorKind::CellOverflow
+            )
+            .unwrap();
+        }
+    }
+    writeln!(code, "        Ok(())").unwrap();
+    writeln!(code, "    }}").unwrap();
+}
+
+fn emit_instructions(code: &mut String, instructions: &[Instruction], depth: usize) {
+    let indent = "    ".repeat(depth);
+    for instruction in instructions {
+        match &instruction.op {
+            Op::Add(n) => writeln!(code, "{}tape.add(tape.index, {})?;", indent, n),
+            Op::Move(n) => writeln!(code, "{}tape.index = tape.at({})?;", indent, n),
+            Op::Output => writeln!(code, "{}tape.output(output)?;", indent),
+            Op::Input => writeln!(code, "{}tape.input(input)?;", indent),
+            Op::Loop(body) => {
+                writeln!(code, "{}while tape.cells[tape.index] != 0 {{", indent).unwrap();
+                emit_instructions(code, body, depth + 1);
+                writeln!(code, "{}}}", indent)
+            }
+            Op::SetZero => writeln!(code, "{}tape.cells[tape.index] = 0;", indent),
+            Op::MulAdd(offset, factor) => {
+                writeln!(code, "{}if tape.cells[tape.index] != 0 {{", indent).unwrap();
+                writeln!(code, "{}    let target = tape.at({})?;", indent, offset).unwrap();
+                writeln!(
+                    code,
+                    "{}    tape.add(target, i64::from(tape.cells[tape.index]) * {})?;",
+                    indent, factor
+                )
+                .unwrap();
+                writeln!(code, "{}}}", indent)
+            }
+            Op::ScanRight | Op::ScanLeft => {
+                let step = if instruction.op == Op::ScanRight {
+                    1
+                } else {
+                    -1
+                };
+                writeln!(code, "{}while tape.cells[tape.index] != 0 {{", indent).unwrap();
+                writeln!(code, "{}    tape.index = tape.at({})?;", indent, step).unwrap();
+                writeln!(code, "{}}}", indent)
+            }
+        }
+        .unwrap();
+    }
+}
+
+// Whether `op` appears anywhere in the program, loop bodies included.
+fn uses(instructions: &[Instruction], op: &Op) -> bool {
+    instructions
+        .iter()
+        .any(|instruction| match &instruction.op {
+            Op::Loop(body) => uses(body, op),
+            other => other == op,
+        })
+}
+
+#[test]
+fn test_emit_rust_matches_interpreter() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let directory =
+        std::env::temp_dir().join(format!("brainfuck-rust-test-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+
+    let wide = Dialect {
+        cell_width: CellWidth::Bits16,
+        eof: crate::dialect::EofBehaviour::MinusOne,
+        ..Default::default()
+    };
+    let strict = Dialect {
+        overflow: OverflowBehaviour::Error,
+        eof: crate::dialect::EofBehaviour::Error,
+        ..Default::default()
+    };
+    let both = Dialect {
+        cell_width: CellWidth::Bits32,
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    let cases = [
+        (
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.",
+            Dialect::default(),
+            "",
+        ),
+        ("-[->+>+<<]>>[-<+>]<+.,.,.", wide, "a"),
+        (",[.,]", strict, "echo"),
+        ("+[->-<]", strict, ""),
+        ("+>\n<<", Dialect::default(), ""),
+        ("+++[-<<++>+++>]<<[->>+<<]>.<[<]+<<<.", both, ""),
+        ("+[>+]", Dialect::default(), ""),
+        ("", Dialect::default(), ""),
+        (".", Dialect::default(), ""),
+    ];
+    for (index, (source, dialect, input)) in cases.into_iter().enumerate() {
+        use crate::engine::Engine;
+        let mut engine = crate::interpreter::InterpreterEngine::new(dialect);
+        engine.prepare(source).unwrap();
+        engine.set_limits(crate::engine::Limits {
+            max_cells: Some(1000),
+            ..Default::default()
+        });
+        let mut expected = Vec::new();
+        let result = engine.run(&mut input.as_bytes(), &mut expected);
+
+        let case_directory = directory.join(index.to_string());
+        std::fs::create_dir_all(&case_directory).unwrap();
+        std::fs::write(
+            case_directory.join("program.rs"),
+            emit_rust(source, dialect, Some(1000)).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            case_directory.join("main.rs"),
+            "mod program;\n\
+             fn main() {\n\
+                 let result = program::run(&mut std::io::stdin(), &mut std::io::stdout());\n\
+                 std::io::Write::flush(&mut std::io::stdout()).unwrap();\n\
+                 if let Err(message) = result {\n\
+                     eprintln!(\"error: {}\", message);\n\
+                     std::process::exit(1);\n\
+                 }\n\
+             }\n",
+        )
+        .unwrap();
+        let executable = case_directory.join("program");
+        let status = Command::new(&rustc)
+            .args(["--edition", "2021", "-D", "warnings", "-o"])
+            .arg(&executable)
+            .arg(case_directory.join("main.rs"))
+            .status()
+            .unwrap();
+        assert!(status.success(), "rustc failed on {}", source);
+
+        let mut child = Command::new(&executable)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        assert_eq!(expected, output.stdout, "output differs on {}", source);
+        match result {
+            Ok(()) => assert!(output.status.success(), "failed on {}", source),
+            Err(error) => {
+                assert_eq!(Some(1), output.status.code(), "did not fail on {}", source);
+                assert_eq!(
+                    format!("error: {}\n", error),
+                    String::from_utf8_lossy(&output.stderr),
+                    "error differs on {}",
+                    source
+                );
+            }
+        }
+    }
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,168 @@
+use crate::dialect::{Dialect, TapeGrowth};
+use crate::error::RuntimeErrorKind;
+
+/// Brainfuck memory shared by every engine.
+///
+/// `memory` holds raw bytes, a cell takes `dialect.cell_width.bytes()` of them. `index` counts cells
+/// from the start of `memory`. The starting cell is at `origin`, which only moves away from zero when
+/// the dialect lets the tape grow to the left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tape {
+    pub index: usize,
+    pub origin: usize,
+    pub memory: Vec<u8>,
+    pub dialect: Dialect,
+    /// Cells the pointer may move away from the starting cell, `None` grows the memory without a limit.
+    pub max_cells: Option<usize>,
+}
+
+impl Tape {
+    pub fn new(dialect: Dialect) -> Tape {
+        Tape {
+            index: 0,
+            origin: 0,
+            memory: vec![0; 1000 * dialect.cell_width.bytes()],
+            dialect,
+            max_cells: None,
+        }
+    }
+
+    /// Number of cells currently allocated.
+    pub fn cell_count(&self) -> usize {
+        self.memory.len() / self.dialect.cell_width.bytes()
+    }
+
+    pub fn cell(&self, index: usize) -> u32 {
+        self.dialect.cell_width.read(&self.memory, index)
+    }
+
+    pub fn set_cell(&mut self, index: usize, value: u32) {
+        self.dialect
+            .cell_width
+            .write(&mut self.memory, index, value);
+    }
+
+    pub fn current_cell(&self) -> u32 {
+        self.cell(self.index)
+    }
+
+    /// Position of the pointer relative to the starting cell, negative on the left of it.
+    pub fn position(&self) -> isize {
+        self.index as isize - self.origin as isize
+    }
+
+    // Add to the cell at `index`, fails when it overflows and the dialect treats overflow as an error.
+    pub(crate) fn add_cell(&mut self, index: usize, value: i64) -> Result<(), RuntimeErrorKind> {
+        let result = self
+            .dialect
+            .add(self.cell(index), value)
+            .ok_or(RuntimeErrorKind::CellOverflow)?;
+        self.set_cell(index, result);
+        Ok(())
+    }
+
+    // Byte offsets from the current one up to here can be used without `reserve_byte`: they are
+    // allocated and right of the starting cell no further than `max_cells` allows.
+    pub(crate) fn memory_end(&self) -> usize {
+        let width = self.dialect.cell_width.bytes();
+        match self.max_cells {
+            Some(max_cells) => self.memory.len().min((self.origin + max_cells) * width),
+            None => self.memory.len(),
+        }
+    }
+
+    // The counterpart of `memory_end` on the left: byte offsets from here up to the current one are
+    // no further left of the starting cell than `max_cells` allows.
+    pub(crate) fn memory_start(&self) -> usize {
+        let width = self.dialect.cell_width.bytes();
+        match self.max_cells {
+            Some(max_cells) => (self.origin + 1).saturating_sub(max_cells) * width,
+            None => 0,
+        }
+    }
+
+    // Index of the cell at `offset` from the current one, growing memory when it is beyond either end.
+    // Growing on the left moves every cell, `index` included.
+    pub(crate) fn offset_index(&mut self, offset: isize) -> Result<usize, RuntimeErrorKind> {
+        let width = self.dialect.cell_width.bytes();
+        let target = self.index as isize + offset;
+        if target < 0 {
+            self.reserve_byte(target * width as isize)?;
+        } else {
+            self.reserve_byte(((target as usize + 1) * width - 1) as isize)?;
+        }
+        Ok(self.index.wrapping_add_signed(offset))
+    }
+
+    // Make sure the byte at `byte_offset` from the start of memory exists, a negative offset grows
+    // the memory on the left. Returns the number of bytes added on the left.
+    pub(crate) fn reserve_byte(&mut self, byte_offset: isize) -> Result<usize, RuntimeErrorKind> {
+        let width = self.dialect.cell_width.bytes() as isize;
+        if byte_offset < 0 && self.dialect.tape == TapeGrowth::Right {
+            return Err(RuntimeErrorKind::PointerUnderflow);
+        }
+        if let Some(max_cells) = self.max_cells {
+            let position = byte_offset.div_euclid(width) - self.origin as isize;
+            if position.unsigned_abs() >= max_cells {
+                return Err(RuntimeErrorKind::MemoryLimitExceeded);
+            }
+        }
+
+        if byte_offset >= 0 {
+            while self.memory.len() <= byte_offset as usize {
+                self.grow(self.memory.len(), false)?;
+            }
+            return Ok(0);
+        }
+
+        let missing = byte_offset.unsigned_abs();
+        let added = missing
+            .max(self.memory.len())
+            .next_multiple_of(width as usize);
+        self.grow(added, true)?;
+        let cells = added / width as usize;
+        self.index += cells;
+        self.origin += cells;
+        Ok(added)
+    }
+
+    // Add `bytes` zeroed bytes at the end, or at the start when `left` is set.
+    fn grow(&mut self, bytes: usize, left: bool) -> Result<(), RuntimeErrorKind> {
+        self.memory
+            .try_reserve_exact(bytes)
+            .map_err(|_| RuntimeErrorKind::MemoryLimitExceeded)?;
+        if left {
+            self.memory.splice(0..0, std::iter::repeat(0).take(bytes));
+        } else {
+            self.memory.resize(self.memory.len() + bytes, 0);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_tape_grows_left() {
+    let dialect = Dialect {
+        cell_width: crate::dialect::CellWidth::Bits16,
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    let mut tape = Tape::new(dialect);
+    tape.memory = vec![0; 6];
+    tape.set_cell(0, 7);
+
+    tape.index = tape.offset_index(-2).unwrap();
+    assert_eq!(6, tape.cell_count());
+    assert_eq!(3, tape.origin);
+    assert_eq!(1, tape.index);
+    assert_eq!(-2, tape.position());
+    assert_eq!(7, tape.cell(tape.origin));
+
+    // the pointer may get 4 cells left of the starting cell, but not 5
+    tape.max_cells = Some(5);
+    assert!(tape.offset_index(-2).is_ok());
+    assert_eq!(
+        Err(RuntimeErrorKind::MemoryLimitExceeded),
+        tape.offset_index(-3)
+    );
+}
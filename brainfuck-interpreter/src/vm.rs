@@ -0,0 +1,243 @@
+use std::io::{Read, Write};
+
+use brainfuck_analyzer::{parse, Instruction, Op, ParseError, Range};
+
+use crate::dialect::Dialect;
+use crate::engine::{Engine, Limits, RunContext};
+use crate::error::{span, ExecutionError, RuntimeError, RuntimeErrorKind};
+use crate::tape::Tape;
+
+/// Runs the lowered instructions as flat bytecode, loops turned into jumps.
+///
+/// Portable like the interpreter, without its recursion and tree walking.
+pub struct VMEngine {
+    program: Bytecode,
+    tape: Tape,
+    limits: Limits,
+}
+
+// One bytecode instruction. Jumps hold the index of the instruction they go to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Code {
+    Add(i32),
+    Move(isize),
+    Output,
+    Input,
+    SetZero,
+    MulAdd(isize, i32),
+    ScanRight,
+    ScanLeft,
+    // `[`, skips to the instruction after the matching `]` when the cell is zero
+    JumpIfZero(u32),
+    // `]`, goes back to the first instruction of the body when the cell is not zero
+    JumpIfNotZero(u32),
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Bytecode {
+    code: Vec<Code>,
+    // source of every instruction, only read to report errors. Both jumps of a loop have its range.
+    sites: Vec<Range>,
+}
+
+impl Bytecode {
+    fn assemble(instructions: &[Instruction]) -> Bytecode {
+        let mut bytecode = Bytecode::default();
+        bytecode.push_all(instructions);
+        bytecode
+    }
+
+    fn push_all(&mut self, instructions: &[Instruction]) {
+        for instruction in instructions {
+            let range = span(&instruction.ranges);
+            let code = match &instruction.op {
+                Op::Add(n) => Code::Add(*n),
+                Op::Move(n) => Code::Move(*n),
+                Op::Output => Code::Output,
+                Op::Input => Code::Input,
+                Op::SetZero => Code::SetZero,
+                Op::MulAdd(offset, factor) => Code::MulAdd(*offset, *factor),
+                Op::ScanRight => Code::ScanRight,
+                Op::ScanLeft => Code::ScanLeft,
+                Op::Loop(body) => {
+                    let start = self.code.len();
+                    self.push(Code::JumpIfZero(0), range);
+                    self.push_all(body);
+                    self.push(Code::JumpIfNotZero(start as u32 + 1), range);
+                    self.code[start] = Code::JumpIfZero(self.code.len() as u32);
+                    continue;
+                }
+            };
+            self.push(code, range);
+        }
+    }
+
+    fn push(&mut self, code: Code, range: Range) {
+        self.code.push(code);
+        self.sites.push(range);
+    }
+}
+
+impl VMEngine {
+    pub fn new(dialect: Dialect) -> VMEngine {
+        VMEngine {
+            program: Bytecode::default(),
+            tape: Tape::new(dialect),
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Engine for VMEngine {
+    fn prepare(&mut self, source: &str) -> Result<(), ParseError> {
+        let parse_result = parse(source)?;
+        let instructions = self
+            .tape
+            .dialect
+            .instructions(&parse_result.parse_token_group);
+        self.program = Bytecode::assemble(&instructions);
+        self.tape = Tape::new(self.tape.dialect);
+        Ok(())
+    }
+
+    fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), RuntimeError> {
+        let mut context = RunContext::new(&mut self.tape, input, output, &self.limits);
+        let mut pc = 0;
+        let mut result = Ok(());
+        while let Some(code) = self.program.code.get(pc) {
+            if let Err(kind) = execute(&mut context, *code, &mut pc) {
+                result = Err(RuntimeError {
+                    kind,
+                    range: self.program.sites[pc],
+                });
+                break;
+            }
+        }
+        context.flush_output();
+        result
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    fn tape_mut(&mut self) -> &mut Tape {
+        &mut self.tape
+    }
+}
+
+// Execute the instruction at `pc` and move `pc` on. On failure `pc` stays at the instruction.
+fn execute(context: &mut RunContext, code: Code, pc: &mut usize) -> Result<(), RuntimeErrorKind> {
+    context.step(1)?;
+    let tape = &mut *context.tape;
+    match code {
+        Code::Add(n) => tape.add_cell(tape.index, n as i64)?,
+        Code::Move(n) => tape.index = tape.offset_index(n)?,
+        Code::Output => context.output_cell(context.tape.index),
+        Code::Input => context.input_cell(context.tape.index)?,
+        Code::SetZero => tape.set_cell(tape.index, 0),
+        Code::MulAdd(offset, factor) => {
+            let value = tape.current_cell();
+            if value != 0 {
+                let target = tape.offset_index(offset)?;
+                tape.add_cell(target, value as i64 * factor as i64)?;
+            }
+        }
+        Code::ScanRight | Code::ScanLeft => {
+            let offset = if code == Code::ScanRight { 1 } else { -1 };
+            while context.tape.current_cell() != 0 {
+                context.tape.index = context.tape.offset_index(offset)?;
+                context.step(1)?;
+            }
+        }
+        Code::JumpIfZero(target) if tape.current_cell() == 0 => {
+            *pc = target as usize;
+            return Ok(());
+        }
+        Code::JumpIfNotZero(target) if tape.current_cell() != 0 => {
+            *pc = target as usize;
+            return Ok(());
+        }
+        Code::JumpIfZero(_) | Code::JumpIfNotZero(_) => (),
+    }
+    *pc += 1;
+    Ok(())
+}
+
+pub fn interpret_vm(
+    input: &str,
+    dialect: Dialect,
+    mut reader: impl Read,
+    mut writer: impl Write,
+) -> Result<(), ExecutionError> {
+    let mut engine = VMEngine::new(dialect);
+    engine.prepare(input)?;
+    engine.run(&mut reader, &mut writer)?;
+    Ok(())
+}
+
+#[test]
+fn test_vm_bytecode() {
+    let instructions =
+        Dialect::default().instructions(&parse("+[>,[-]<-]>[>]").unwrap().parse_token_group);
+    let code = Bytecode::assemble(&instructions).code;
+    assert_eq!(
+        vec![
+            Code::Add(1),
+            Code::JumpIfZero(8),
+            Code::Move(1),
+            Code::Input,
+            Code::SetZero,
+            Code::Move(-1),
+            Code::Add(-1),
+            Code::JumpIfNotZero(2),
+            Code::Move(1),
+            Code::ScanRight,
+        ],
+        code
+    );
+}
+
+#[test]
+fn test_vm_matches_interpreter() {
+    use crate::InterpreterEngine;
+
+    let sources = [
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.",
+        ",[.,]",
+        "+++[->++>+++<<]>>[-<+>]<[<]>>,.",
+        "+[[]]",
+        "+>+\n[<]",
+    ];
+    for source in sources {
+        for limits in [
+            Limits {
+                max_steps: Some(10_000),
+                ..Default::default()
+            },
+            Limits {
+                max_steps: Some(30),
+                ..Default::default()
+            },
+        ] {
+            let mut expected_output = Vec::new();
+            let mut interpreter = InterpreterEngine::new(Dialect::default());
+            interpreter.prepare(source).unwrap();
+            interpreter.set_limits(limits);
+            let expected = interpreter.run(&mut "echo".as_bytes(), &mut expected_output);
+
+            let mut output = Vec::new();
+            let mut engine = VMEngine::new(Dialect::default());
+            engine.prepare(source).unwrap();
+            engine.set_limits(limits);
+            let result = engine.run(&mut "echo".as_bytes(), &mut output);
+            assert_eq!(expected, result, "on {}", source);
+            assert_eq!(expected_output, output, "on {}", source);
+            assert_eq!(interpreter.tape(), engine.tape(), "on {}", source);
+        }
+    }
+}
@@ -0,0 +1,628 @@
+use std::fmt::Write;
+
+use crate::dialect::{CellWidth, Dialect, OverflowBehaviour, TapeGrowth};
+use crate::error::RuntimeErrorKind;
+use brainfuck_analyzer::{parse, Instruction, Op, ParseError, Range};
+
+// Locals of `run`: the byte offset of the current cell, the offset of another cell and a cell value
+// widened to 64 bits, which makes overflow visible for every cell width.
+const POINTER: u32 = 0;
+const TARGET: u32 = 1;
+const VALUE: u32 = 2;
+
+// Imported functions come first in the function index space, `run` follows them.
+const PUTCHAR: u32 = 0;
+const GETCHAR: u32 = 1;
+
+const PAGE_BYTES: usize = 65536;
+
+// The subset of WebAssembly the generated code uses, kept as a list so the text and the binary
+// format are written from the same code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Wasm {
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Call(u32),
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    Load(CellWidth),
+    Store(CellWidth),
+    I32Const(i32),
+    I64Const(i64),
+    I32Eqz,
+    I32LtS,
+    I32GeU,
+    I32Add,
+    I32And,
+    I32WrapI64,
+    I64ExtendI32U,
+    I64Add,
+    I64Mul,
+    I64GtU,
+}
+
+/// Translates the program into a WebAssembly module in the text format.
+///
+/// The module imports `putchar` (taking a byte) and `getchar` (returning a byte or -1 at the end of
+/// input) from `env`, and exports its tape as `memory` and the program as `run`. `run` returns 0 when
+/// the program ends, or the code of the error that stopped it: 1 pointer underflow, 2 cell overflow,
+/// 3 end of input, 4 memory limit exceeded. The tape has room for `max_cells` cells on each side
+/// the dialect lets it grow to, as in the executables of `compile_elf`.
+pub fn emit_wat(source: &str, dialect: Dialect, max_cells: usize) -> Result<String, ParseError> {
+    let (code, pages) = compile(source, dialect, max_cells)?;
+
+    let mut text = String::new();
+    writeln!(text, "(module").unwrap();
+    writeln!(text, "  (type (;0;) (func (param i32)))").unwrap();
+    writeln!(text, "  (type (;1;) (func (result i32)))").unwrap();
+    writeln!(text, "  (import \"env\" \"putchar\" (func (;0;) (type 0)))").unwrap();
+    writeln!(text, "  (import \"env\" \"getchar\" (func (;1;) (type 1)))").unwrap();
+    writeln!(text, "  (func (;2;) (type 1) (local i32 i32 i64)").unwrap();
+    let mut depth = 2;
+    for instruction in code {
+        if matches!(instruction, Wasm::Else | Wasm::End) {
+            depth -= 1;
+        }
+        writeln!(text, "{}{}", "  ".repeat(depth), text_of(instruction)).unwrap();
+        if matches!(
+            instruction,
+            Wasm::Block | Wasm::Loop | Wasm::If | Wasm::Else
+        ) {
+            depth += 1;
+        }
+    }
+    writeln!(text, "  )").unwrap();
+    writeln!(text, "  (memory (;0;) {})", pages).unwrap();
+    writeln!(text, "  (export \"run\" (func 2))").unwrap();
+    writeln!(text, "  (export \"memory\" (memory 0))").unwrap();
+    writeln!(text, ")").unwrap();
+    Ok(text)
+}
+
+/// Translates the program into a WebAssembly module in the binary format, the same module as
+/// `emit_wat` describes.
+pub fn emit_wasm(source: &str, dialect: Dialect, max_cells: usize) -> Result<Vec<u8>, ParseError> {
+    let (code, pages) = compile(source, dialect, max_cells)?;
+
+    let mut module = b"\0asm".to_vec();
+    module.extend_from_slice(&1u32.to_le_bytes());
+
+    // types: (i32) -> (), () -> i32
+    push_section(&mut module, 1, &[2, 0x60, 1, 0x7f, 0, 0x60, 0, 1, 0x7f]);
+
+    let mut imports = vec![2];
+    for (name, type_index) in [("putchar", 0), ("getchar", 1)] {
+        push_name(&mut imports, "env");
+        push_name(&mut imports, name);
+        imports.extend_from_slice(&[0, type_index]);
+    }
+    push_section(&mut module, 2, &imports);
+
+    // one function of type () -> i32
+    push_section(&mut module, 3, &[1, 1]);
+
+    // one memory with a minimum and no maximum
+    let mut memories = vec![1, 0];
+    push_unsigned(&mut memories, pages as u64);
+    push_section(&mut module, 5, &memories);
+
+    let mut exports = vec![2];
+    push_name(&mut exports, "run");
+    exports.extend_from_slice(&[0, 2]);
+    push_name(&mut exports, "memory");
+    exports.extend_from_slice(&[2, 0]);
+    push_section(&mut module, 7, &exports);
+
+    // locals: two i32, one i64
+    let mut body = vec![2, 2, 0x7f, 1, 0x7e];
+    for instruction in code {
+        encode(&mut body, instruction);
+    }
+    body.push(0x0b);
+    let mut bodies = vec![1];
+    push_unsigned(&mut bodies, body.len() as u64);
+    bodies.extend_from_slice(&body);
+    push_section(&mut module, 10, &bodies);
+
+    Ok(module)
+}
+
+// What `run` returns when the program stops with an error.
+fn error_code(kind: RuntimeErrorKind) -> i32 {
+    match kind {
+        RuntimeErrorKind::PointerUnderflow => 1,
+        RuntimeErrorKind::CellOverflow => 2,
+        RuntimeErrorKind::EndOfInput => 3,
+        RuntimeErrorKind::MemoryLimitExceeded => 4,
+        RuntimeErrorKind::StepLimitExceeded => 5,
+        RuntimeErrorKind::TimeLimitExceeded => 6,
+    }
+}
+
+// The body of `run` and the number of memory pages its tape needs.
+fn compile(
+    source: &str,
+    dialect: Dialect,
+    max_cells: usize,
+) -> Result<(Vec<Wasm>, usize), ParseError> {
+    let parse_result = parse(source)?;
+    let instructions = dialect.instructions(&parse_result.parse_token_group);
+
+    let (tape_bytes, origin) = tape_layout(dialect, max_cells).ok_or_else(|| ParseError {
+        range: Range::default(),
+        error_message: format!(
+            "A tape of {} cells on each side does not fit into the WebAssembly memory.",
+            max_cells
+        ),
+    })?;
+    let generator = Generator {
+        dialect,
+        tape_bytes,
+    };
+
+    let mut code = vec![Wasm::I32Const(origin), Wasm::LocalSet(POINTER)];
+    generator.emit_instructions(&mut code, &instructions);
+    code.push(Wasm::I32Const(0));
+    Ok((code, (tape_bytes as usize).div_ceil(PAGE_BYTES).max(1)))
+}
+
+// Size of the tape and offset of the starting cell in bytes, `None` when the size is not a positive
+// i32. Offsets are i32 and compared unsigned, which also catches moves left of the tape as long as
+// it stays below 2 GiB.
+fn tape_layout(dialect: Dialect, max_cells: usize) -> Option<(i32, i32)> {
+    let width = dialect.cell_width.bytes();
+    // the starting cell is always there
+    let cells = max_cells.max(1);
+    let (tape_bytes, origin) = match dialect.tape {
+        TapeGrowth::Right => (cells.checked_mul(width)?, 0),
+        TapeGrowth::Both => (
+            cells.checked_mul(2)?.checked_sub(1)?.checked_mul(width)?,
+            (cells - 1) * width,
+        ),
+    };
+    Some((i32::try_from(tape_bytes).ok()?, origin as i32))
+}
+
+struct Generator {
+    dialect: Dialect,
+    tape_bytes: i32,
+}
+
+impl Generator {
+    fn emit_instructions(&self, code: &mut Vec<Wasm>, instructions: &[Instruction]) {
+        let width = self.dialect.cell_width.bytes() as isize;
+        let cell_width = self.dialect.cell_width;
+        for instruction in instructions {
+            match &instruction.op {
+                Op::Add(n) => self.emit_add(code, POINTER, &[Wasm::I64Const(*n as i64)]),
+                Op::Move(n) => {
+                    self.emit_offset(code, n * width);
+                    code.extend_from_slice(&[Wasm::LocalGet(TARGET), Wasm::LocalSet(POINTER)]);
+                }
+                Op::Output => code.extend_from_slice(&[
+                    Wasm::LocalGet(POINTER),
+                    Wasm::Load(cell_width),
+                    Wasm::I32Const(0xff),
+                    Wasm::I32And,
+                    Wasm::Call(PUTCHAR),
+                ]),
+                Op::Input => self.emit_input(code),
+                Op::Loop(body) => {
+                    self.emit_loop_start(code);
+                    self.emit_instructions(code, body);
+                    code.extend_from_slice(&[Wasm::Br(0), Wasm::End, Wasm::End]);
+                }
+                Op::SetZero => code.extend_from_slice(&[
+                    Wasm::LocalGet(POINTER),
+                    Wasm::I32Const(0),
+                    Wasm::Store(cell_width),
+                ]),
+                Op::MulAdd(offset, factor) => {
+                    code.extend_from_slice(&[
+                        Wasm::LocalGet(POINTER),
+                        Wasm::Load(cell_width),
+                        Wasm::If,
+                    ]);
+                    self.emit_offset(code, offset * width);
+                    self.emit_add(
+                        code,
+                        TARGET,
+                        &[
+                            Wasm::LocalGet(POINTER),
+                            Wasm::Load(cell_width),
+                            Wasm::I64ExtendI32U,
+                            Wasm::I64Const(*factor as i64),
+                            Wasm::I64Mul,
+                        ],
+                    );
+                    code.push(Wasm::End);
+                }
+                Op::ScanRight | Op::ScanLeft => {
+                    let step = if instruction.op == Op::ScanRight {
+                        width
+                    } else {
+                        -width
+                    };
+                    self.emit_loop_start(code);
+                    self.emit_offset(code, step);
+                    code.extend_from_slice(&[
+                        Wasm::LocalGet(TARGET),
+                        Wasm::LocalSet(POINTER),
+                        Wasm::Br(0),
+                        Wasm::End,
+                        Wasm::End,
+                    ]);
+                }
+            }
+        }
+    }
+
+    // Enter a loop leaving once the current cell is zero, closed by `br 0, end, end`.
+    fn emit_loop_start(&self, code: &mut Vec<Wasm>) {
+        code.extend_from_slice(&[
+            Wasm::Block,
+            Wasm::Loop,
+            Wasm::LocalGet(POINTER),
+            Wasm::Load(self.dialect.cell_width),
+            Wasm::I32Eqz,
+            Wasm::BrIf(1),
+        ]);
+    }
+
+    // TARGET = POINTER + bytes, returning an error when that is off the tape. Left of the tape the
+    // sum wraps around and is past its end as well.
+    fn emit_offset(&self, code: &mut Vec<Wasm>, bytes: isize) {
+        let kind = match (bytes < 0, self.dialect.tape) {
+            (true, TapeGrowth::Right) => RuntimeErrorKind::PointerUnderflow,
+            _ => RuntimeErrorKind::MemoryLimitExceeded,
+        };
+        code.extend_from_slice(&[
+            Wasm::LocalGet(POINTER),
+            Wasm::I32Const(bytes as i32),
+            Wasm::I32Add,
+            Wasm::LocalTee(TARGET),
+            Wasm::I32Const(self.tape_bytes),
+            Wasm::I32GeU,
+            Wasm::If,
+            Wasm::I32Const(error_code(kind)),
+            Wasm::Return,
+            Wasm::End,
+        ]);
+    }
+
+    // Add the i64 `value` leaves on the stack to the cell at the offset in `local`.
+    fn emit_add(&self, code: &mut Vec<Wasm>, local: u32, value: &[Wasm]) {
+        let cell_width = self.dialect.cell_width;
+        code.extend_from_slice(&[
+            Wasm::LocalGet(local),
+            Wasm::Load(cell_width),
+            Wasm::I64ExtendI32U,
+        ]);
+        code.extend_from_slice(value);
+        code.extend_from_slice(&[Wasm::I64Add, Wasm::LocalSet(VALUE)]);
+        if self.dialect.overflow == OverflowBehaviour::Error {
+            // results below zero wrap around and are too large as well
+            code.extend_from_slice(&[
+                Wasm::LocalGet(VALUE),
+                Wasm::I64Const(cell_width.max_value() as i64),
+                Wasm::I64GtU,
+                Wasm::If,
+                Wasm::I32Const(error_code(RuntimeErrorKind::CellOverflow)),
+                Wasm::Return,
+                Wasm::End,
+            ]);
+        }
+        // stores keep the low bits, which wraps the value
+        code.extend_from_slice(&[
+            Wasm::LocalGet(local),
+            Wasm::LocalGet(VALUE),
+            Wasm::I32WrapI64,
+            Wasm::Store(cell_width),
+        ]);
+    }
+
+    fn emit_input(&self, code: &mut Vec<Wasm>) {
+        let cell_width = self.dialect.cell_width;
+        code.extend_from_slice(&[
+            Wasm::Call(GETCHAR),
+            Wasm::LocalTee(TARGET),
+            Wasm::I32Const(0),
+            Wasm::I32LtS,
+            Wasm::If,
+        ]);
+        match self.dialect.eof_value() {
+            Ok(None) => (),
+            Ok(Some(value)) => code.extend_from_slice(&[
+                Wasm::LocalGet(POINTER),
+                Wasm::I32Const(value as i32),
+                Wasm::Store(cell_width),
+            ]),
+            Err(kind) => code.extend_from_slice(&[Wasm::I32Const(error_code(kind)), Wasm::Return]),
+        }
+        code.extend_from_slice(&[
+            Wasm::Else,
+            Wasm::LocalGet(POINTER),
+            Wasm::LocalGet(TARGET),
+            Wasm::Store(cell_width),
+            Wasm::End,
+        ]);
+    }
+}
+
+fn text_of(instruction: Wasm) -> String {
+    match instruction {
+        Wasm::Block => "block".to_string(),
+        Wasm::Loop => "loop".to_string(),
+        Wasm::If => "if".to_string(),
+        Wasm::Else => "else".to_string(),
+        Wasm::End => "end".to_string(),
+        Wasm::Br(depth) => format!("br {}", depth),
+        Wasm::BrIf(depth) => format!("br_if {}", depth),
+        Wasm::Return => "return".to_string(),
+        Wasm::Call(function) => format!("call {}", function),
+        Wasm::LocalGet(local) => format!("local.get {}", local),
+        Wasm::LocalSet(local) => format!("local.set {}", local),
+        Wasm::LocalTee(local) => format!("local.tee {}", local),
+        Wasm::Load(CellWidth::Bits8) => "i32.load8_u".to_string(),
+        Wasm::Load(CellWidth::Bits16) => "i32.load16_u".to_string(),
+        Wasm::Load(CellWidth::Bits32) => "i32.load".to_string(),
+        Wasm::Store(CellWidth::Bits8) => "i32.store8".to_string(),
+        Wasm::Store(CellWidth::Bits16) => "i32.store16".to_string(),
+        Wasm::Store(CellWidth::Bits32) => "i32.store".to_string(),
+        Wasm::I32Const(value) => format!("i32.const {}", value),
+        Wasm::I64Const(value) => format!("i64.const {}", value),
+        Wasm::I32Eqz => "i32.eqz".to_string(),
+        Wasm::I32LtS => "i32.lt_s".to_string(),
+        Wasm::I32GeU => "i32.ge_u".to_string(),
+        Wasm::I32Add => "i32.add".to_string(),
+        Wasm::I32And => "i32.and".to_string(),
+        Wasm::I32WrapI64 => "i32.wrap_i64".to_string(),
+        Wasm::I64ExtendI32U => "i64.extend_i32_u".to_string(),
+        Wasm::I64Add => "i64.add".to_string(),
+        Wasm::I64Mul => "i64.mul".to_string(),
+        Wasm::I64GtU => "i64.gt_u".to_string(),
+    }
+}
+
+// ref data: https://webassembly.github.io/spec/core/binary/instructions.html
+fn encode(body: &mut Vec<u8>, instruction: Wasm) {
+    // loads and stores are followed by the alignment, log2 of the cell width, and an offset of 0
+    let memory_argument = |width: CellWidth| [width.bytes().trailing_zeros() as u8, 0];
+    match instruction {
+        Wasm::Block => body.extend_from_slice(&[0x02, 0x40]),
+        Wasm::Loop => body.extend_from_slice(&[0x03, 0x40]),
+        Wasm::If => body.extend_from_slice(&[0x04, 0x40]),
+        Wasm::Else => body.push(0x05),
+        Wasm::End => body.push(0x0b),
+        Wasm::Br(depth) => {
+            body.push(0x0c);
+            push_unsigned(body, depth as u64);
+        }
+        Wasm::BrIf(depth) => {
+            body.push(0x0d);
+            push_unsigned(body, depth as u64);
+        }
+        Wasm::Return => body.push(0x0f),
+        Wasm::Call(function) => {
+            body.push(0x10);
+            push_unsigned(body, function as u64);
+        }
+        Wasm::LocalGet(local) => {
+            body.push(0x20);
+            push_unsigned(body, local as u64);
+        }
+        Wasm::LocalSet(local) => {
+            body.push(0x21);
+            push_unsigned(body, local as u64);
+        }
+        Wasm::LocalTee(local) => {
+            body.push(0x22);
+            push_unsigned(body, local as u64);
+        }
+        Wasm::Load(width) => {
+            body.push(match width {
+                CellWidth::Bits8 => 0x2d,
+                CellWidth::Bits16 => 0x2f,
+                CellWidth::Bits32 => 0x28,
+            });
+            body.extend_from_slice(&memory_argument(width));
+        }
+        Wasm::Store(width) => {
+            body.push(match width {
+                CellWidth::Bits8 => 0x3a,
+                CellWidth::Bits16 => 0x3b,
+                CellWidth::Bits32 => 0x36,
+            });
+            body.extend_from_slice(&memory_argument(width));
+        }
+        Wasm::I32Const(value) => {
+            body.push(0x41);
+            push_signed(body, value as i64);
+        }
+        Wasm::I64Const(value) => {
+            body.push(0x42);
+            push_signed(body, value);
+        }
+        Wasm::I32Eqz => body.push(0x45),
+        Wasm::I32LtS => body.push(0x48),
+        Wasm::I32GeU => body.push(0x4f),
+        Wasm::I32Add => body.push(0x6a),
+        Wasm::I32And => body.push(0x71),
+        Wasm::I32WrapI64 => body.push(0xa7),
+        Wasm::I64ExtendI32U => body.push(0xad),
+        Wasm::I64Add => body.push(0x7c),
+        Wasm::I64Mul => body.push(0x7e),
+        Wasm::I64GtU => body.push(0x56),
+    }
+}
+
+fn push_section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    push_unsigned(module, contents.len() as u64);
+    module.extend_from_slice(contents);
+}
+
+fn push_name(bytes: &mut Vec<u8>, name: &str) {
+    push_unsigned(bytes, name.len() as u64);
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+// LEB128, seven bits per byte starting with the lowest, the high bit marks that more follow.
+fn push_unsigned(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+// Signed LEB128, done once the remaining bits only repeat the sign bit of the last byte.
+fn push_signed(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let sign_bit = byte & 0x40 != 0;
+        if (value == 0 && !sign_bit) || (value == -1 && sign_bit) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[test]
+fn test_wat_and_wasm_agree() {
+    let sources = [
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.",
+        ",[.,]+[->>++<<]>[-]<[<]>>[>]",
+    ];
+    let dialects = [
+        Dialect::default(),
+        Dialect {
+            cell_width: CellWidth::Bits32,
+            overflow: OverflowBehaviour::Error,
+            eof: crate::dialect::EofBehaviour::MinusOne,
+            tape: TapeGrowth::Both,
+        },
+        Dialect {
+            cell_width: CellWidth::Bits16,
+            eof: crate::dialect::EofBehaviour::Error,
+            ..Default::default()
+        },
+    ];
+    for source in sources {
+        for dialect in dialects {
+            let binary = emit_wasm(source, dialect, 100_000).unwrap();
+            wasmparser::Validator::new()
+                .validate_all(&binary)
+                .unwrap_or_else(|error| panic!("{} on {}", error, source));
+
+            let text = emit_wat(source, dialect, 100_000).unwrap();
+            assert_eq!(wat::parse_str(&text).unwrap(), binary, "on {}", source);
+        }
+    }
+}
+
+#[test]
+fn test_wasm_code() {
+    use Wasm::*;
+
+    let dialect = Dialect {
+        cell_width: CellWidth::Bits16,
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    // 5 cells of 2 bytes, starting in the middle
+    let (code, pages) = compile("+[<]", dialect, 3).unwrap();
+    assert_eq!(1, pages);
+    let width = CellWidth::Bits16;
+    assert_eq!(
+        vec![
+            I32Const(4),
+            LocalSet(POINTER),
+            // +
+            LocalGet(POINTER),
+            Load(width),
+            I64ExtendI32U,
+            I64Const(1),
+            I64Add,
+            LocalSet(VALUE),
+            LocalGet(POINTER),
+            LocalGet(VALUE),
+            I32WrapI64,
+            Store(width),
+            // [<]
+            Block,
+            Loop,
+            LocalGet(POINTER),
+            Load(width),
+            I32Eqz,
+            BrIf(1),
+            LocalGet(POINTER),
+            I32Const(-2),
+            I32Add,
+            LocalTee(TARGET),
+            I32Const(10),
+            I32GeU,
+            If,
+            I32Const(error_code(RuntimeErrorKind::MemoryLimitExceeded)),
+            Return,
+            End,
+            LocalGet(TARGET),
+            LocalSet(POINTER),
+            Br(0),
+            End,
+            End,
+            I32Const(0),
+        ],
+        code
+    );
+}
+
+#[test]
+fn test_wasm_tape_too_large() {
+    assert_eq!(
+        Some((i32::MAX, 0)),
+        tape_layout(Dialect::default(), i32::MAX as usize)
+    );
+    assert_eq!(None, tape_layout(Dialect::default(), 1 << 31));
+    let wide = Dialect {
+        cell_width: CellWidth::Bits32,
+        tape: TapeGrowth::Both,
+        ..Default::default()
+    };
+    assert_eq!(
+        Some((i32::MAX - 3, (1 << 30) - 4)),
+        tape_layout(wide, 1 << 28)
+    );
+    assert_eq!(None, tape_layout(wide, (1 << 28) + 1));
+    assert_eq!(None, tape_layout(wide, usize::MAX));
+    let error = emit_wasm("+", wide, usize::MAX).unwrap_err();
+    assert!(error.error_message.contains("does not fit"));
+}
+
+#[test]
+fn test_leb128() {
+    let mut bytes = vec![];
+    push_unsigned(&mut bytes, 624485);
+    assert_eq!(vec![0xe5, 0x8e, 0x26], bytes);
+
+    bytes.clear();
+    push_signed(&mut bytes, -123456);
+    assert_eq!(vec![0xc0, 0xbb, 0x78], bytes);
+
+    bytes.clear();
+    push_signed(&mut bytes, 64);
+    assert_eq!(vec![0xc0, 0x00], bytes);
+}
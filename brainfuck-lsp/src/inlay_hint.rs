@@ -1,4 +1,4 @@
-use brainfuck_analyzer::{ParseError, Position, Token, TokenGroup, TokenType};
+use brainfuck_analyzer::{Position, Token, TokenGroup, TokenType};
 use core::slice::Iter;
 
 #[derive(Debug, PartialEq)]
@@ -8,9 +8,10 @@ pub struct InlayHint {
 }
 
 impl InlayHint {
-    pub fn inlay_hint_string(input: &str) -> Result<Vec<InlayHint>, ParseError> {
-        let token_group = brainfuck_analyzer::parse(input)?;
-        Ok(Self::_inlay_hint(&token_group.parse_token_group))
+    /// Hints are computed on the best-effort tree, so a file with syntax errors still gets hints.
+    pub fn inlay_hint_string(input: &str) -> Vec<InlayHint> {
+        let (token_group, _) = brainfuck_analyzer::parse_with_recovery(input);
+        Self::_inlay_hint(&token_group.parse_token_group)
     }
 
     pub fn _inlay_hint(token_group: &TokenGroup) -> Vec<InlayHint> {
@@ -365,5 +366,11 @@ impl<'a> TokenIter<'a> {
 fn test_inlay_hint_string_should_success() {
     let actual = InlayHint::inlay_hint_string(">>+,,..>>[<]");
     print!("Actual value:\n{:?}", actual);
-    assert_eq!(6, actual.unwrap().len());
+    assert_eq!(6, actual.len());
+}
+
+#[test]
+fn test_inlay_hint_string_with_syntax_error() {
+    let actual = InlayHint::inlay_hint_string(">>+,,..>>[<");
+    assert_eq!(6, actual.len());
 }
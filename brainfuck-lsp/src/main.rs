@@ -217,36 +217,28 @@ impl Backend {
         self.client
             .log_message(MessageType::INFO, format!("{:?}", url.to_string()))
             .await;
-        let mut err = None;
+        let mut errs = vec![];
         let mut version = 0;
         {
             let hash_map = self.text_documents.lock().unwrap();
             if let Some(contents) = hash_map.get(&url.to_string()) {
-                let format_res = brainfuck_analyzer::parse(&contents.text);
-                if let Err(parse_error) = format_res {
-                    err = Some(parse_error);
-                    version = contents.version;
-                }
+                let (_, parse_errors) = brainfuck_analyzer::parse_with_recovery(&contents.text);
+                errs = parse_errors;
+                version = contents.version;
             }
         }
-        if let Some(err) = err {
-            self.client
-                .publish_diagnostics(
-                    url,
-                    vec![Diagnostic {
-                        range: convert_range(err.range),
-                        severity: Some(DiagnosticSeverity::ERROR),
-                        message: err.error_message,
-                        ..Default::default()
-                    }],
-                    Some(version),
-                )
-                .await;
-        } else {
-            self.client
-                .publish_diagnostics(url, vec![], Some(version))
-                .await;
-        }
+        let diagnostics = errs
+            .into_iter()
+            .map(|err| Diagnostic {
+                range: convert_range(err.range),
+                severity: Some(DiagnosticSeverity::ERROR),
+                message: err.error_message,
+                ..Default::default()
+            })
+            .collect();
+        self.client
+            .publish_diagnostics(url, diagnostics, Some(version))
+            .await;
     }
 
     async fn inlay_hint(&self, params: InlayHintParams) -> Result<Vec<InlayHint>> {
@@ -267,29 +259,13 @@ impl Backend {
             .await;
 
         let url = params.text_document.uri.to_string();
-        let res;
-        let mut err = None;
-        {
-            let hash_map = self.text_documents.lock().unwrap();
-
-            res = if let Some(contents) = hash_map.get(&url) {
-                let inlay_hint_res = inlay_hint::InlayHint::inlay_hint_string(&contents.text);
-
-                match inlay_hint_res {
-                    Ok(f) => Ok(f.into_iter().map(convert_inlay_hint).collect()),
-                    Err(e) => {
-                        err = Some(e);
-                        Ok(Vec::new())
-                    }
-                }
-            } else {
-                Ok(Vec::new())
-            };
+        let hash_map = self.text_documents.lock().unwrap();
+        if let Some(contents) = hash_map.get(&url) {
+            let inlay_hints = inlay_hint::InlayHint::inlay_hint_string(&contents.text);
+            Ok(inlay_hints.into_iter().map(convert_inlay_hint).collect())
+        } else {
+            Ok(Vec::new())
         }
-        self.client
-            .log_message(MessageType::INFO, format!("err = {:?}", err))
-            .await;
-        res
     }
 }
 